@@ -1,44 +1,336 @@
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use structopt::StructOpt;
+use tokio::io::{AsyncBufReadExt, BufReader, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+#[path="../cluster.rs"]
+mod cluster;
+#[path="../codec.rs"]
+mod codec;
 #[path="../util.rs"]
 mod util;
 
+/// How many times a request follows a redirect before the client gives up on it
+const MAX_REDIRECTS: u8 = 3;
+/// How many times a request that timed out is sent again. Retries carry the session of the
+/// original request, so a request that was decided after all is not applied twice.
+const MAX_RETRIES: u8 = 2;
+
+/// Request waiting for a reply, kept so it can be resent
+struct InFlight {
+    request: util::ClientRequest,
+    node: u64,
+    redirects: u8,
+    retries: u8,
+}
+
 #[tokio::main]
 async fn main() {
-    // Spawns a task to print output from the command window
-    tokio::spawn(cmd_listener());
+    let opt = cluster::ClusterOpt::from_args();
+    let cluster = match cluster::Cluster::from_opt(&opt) {
+        Ok(cluster) => cluster,
+        Err(e) => {
+            eprintln!("Failed to load cluster configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     println!("CMD client started, waiting for commands");
 
+    // one connection per server node, replies come back on the same connection
+    let mut connections: HashMap<u64, WriteHalf<TcpStream>> = HashMap::new();
+    let mut next_request_id: u64 = 0;
+    // requests waiting for a reply, kept so they can be resent if a node redirects them or they time out
+    let mut in_flight: HashMap<u64, InFlight> = HashMap::new();
+    // requests are deduplicated by session, the request id serves as sequence number
+    let client_id = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+        ^ ((std::process::id() as u64) << 32);
+
+    let (response_sender, mut responses) = mpsc::channel::<(u64, util::ClientResponse)>(32);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
     loop {
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).expect(&*format!("Failed to read input: {}", input));
+        tokio::select! {
+            line = lines.next_line() => {
+                let input = match line {
+                    Ok(Some(input)) => input,
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("Failed to read input: {}", e);
+                        break;
+                    }
+                };
+                let (id, message) = match input.trim().split_once(' ') {
+                    Some((id, message)) => (id, message.trim()),
+                    None => {
+                        eprintln!("Usage: <NODE> <OP> <ARGS>");
+                        continue;
+                    }
+                };
+                let id: u64 = match id.parse() {
+                    Ok(id) => id,
+                    Err(..) => {
+                        eprintln!("Failed to read ID from input string: {}", input);
+                        continue;
+                    }
+                };
+                let args = match tokenize(message) {
+                    Ok(args) => args,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+
+                let session = Some(util::Session { client: client_id, seq: next_request_id });
+                let request = util::ClientRequest { id: next_request_id, args, session };
+                next_request_id += 1;
+                if send(&cluster, &mut connections, &response_sender, id, &request).await {
+                    in_flight.insert(request.id, InFlight { request, node: id, redirects: 0, retries: 0 });
+                }
+            }
+            Some((node, message)) = responses.recv() => {
+                if let Some(pending) = in_flight.get_mut(&message.id) {
+                    match message.response {
+                        util::Response::Redirect { leader } if pending.redirects < MAX_REDIRECTS => {
+                            pending.redirects += 1;
+                            pending.node = leader;
+                            println!("[request {}] Server {} redirected to leader {}", message.id, node, leader);
+                            if !send(&cluster, &mut connections, &response_sender, leader, &pending.request).await {
+                                in_flight.remove(&message.id);
+                            }
+                            continue;
+                        }
+                        util::Response::Timeout if pending.retries < MAX_RETRIES => {
+                            pending.retries += 1;
+                            println!("[request {}] Timed out on server {}, retrying", message.id, node);
+                            if !send(&cluster, &mut connections, &response_sender, pending.node, &pending.request).await {
+                                in_flight.remove(&message.id);
+                            }
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+                // watches keep replying to the same request
+                if !matches!(message.response, util::Response::Watch(..)) {
+                    in_flight.remove(&message.id);
+                }
+                print_response(message);
+            }
+        }
+    }
+}
 
-        let id: u64 = input.split_whitespace().next().and_then(|s| s.parse().ok()).expect(&*format!("Failed to read ID from input string: {}", input));
+/// Send a request to a node, connecting to it first if this is the first request it gets. Returns
+/// whether the request was sent, a broken connection is dropped so the next request reconnects.
+async fn send(
+    cluster: &cluster::Cluster,
+    connections: &mut HashMap<u64, WriteHalf<TcpStream>>,
+    response_sender: &mpsc::Sender<(u64, util::ClientResponse)>,
+    id: u64,
+    request: &util::ClientRequest,
+) -> bool {
+    let writer = match connections.entry(id) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let addr = cluster.node(id).client;
+            println!("Connecting to server on addr: {}", addr);
+            let stream = match TcpStream::connect(&addr).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("[request {}] Failed to connect to server {}: {}", request.id, id, e);
+                    return false;
+                }
+            };
+            let (reader, writer) = tokio::io::split(stream);
+            tokio::spawn(response_listener(id, reader, response_sender.clone()));
+            entry.insert(writer)
+        }
+    };
+
+    println!("Sending request {} to server {}", request.id, id);
+    if let Err(e) = codec::write_message(writer, request).await {
+        println!("[request {}] Failed to send to server {}: {}", request.id, id, e);
+        connections.remove(&id);
+        return false;
+    }
+    true
+}
 
-        let addr = format!("{}:{}", "127.0.0.1", util::CMD_PORT_BASE + id);
-        println!("Sending message to server on addr: {}", addr);
-        let stream = TcpStream::connect(addr).await.unwrap();
-        let mut writer = tokio::io::split(stream).1;
-        let message = input[2..].trim();
-        let message_bytes = bincode::serialize(&message).unwrap();
-        writer.write_all(&message_bytes).await.unwrap();
+/// Forward the responses a server sends back on its connection to the main loop
+async fn response_listener(
+    node: u64,
+    mut reader: ReadHalf<TcpStream>,
+    sender: mpsc::Sender<(u64, util::ClientResponse)>,
+) {
+    while let Ok(Some(message)) = codec::read_message::<_, util::ClientResponse>(&mut reader).await {
+        if sender.send((node, message)).await.is_err() {
+            return;
+        }
+    }
+    println!("Connection to server {} closed", node);
+}
+
+/// Split a command line into arguments. Double quotes keep spaces in an argument, `\"`, `\\`,
+/// `\n`, `\t` and `\xNN` escape a byte, and an unquoted `@<PATH>` argument is replaced by the
+/// contents of the file.
+fn tokenize(line: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut args = vec![];
+    let mut bytes = line.bytes().peekable();
+    loop {
+        while bytes.next_if(|b| b.is_ascii_whitespace()).is_some() {}
+        if bytes.peek().is_none() {
+            return Ok(args);
+        }
+        let mut arg = vec![];
+        let mut quoted = false;
+        let mut was_quoted = false;
+        while let Some(b) = bytes.next() {
+            match b {
+                b'"' => {
+                    quoted = !quoted;
+                    was_quoted = true;
+                }
+                b'\\' => match bytes.next() {
+                    Some(b'n') => arg.push(b'\n'),
+                    Some(b't') => arg.push(b'\t'),
+                    Some(b'x') => {
+                        let hex = [bytes.next().unwrap_or_default(), bytes.next().unwrap_or_default()];
+                        let byte = std::str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                        arg.push(byte.ok_or("\\x expects two hex digits")?);
+                    }
+                    Some(escaped) => arg.push(escaped),
+                    None => return Err("Trailing \\ in command".into()),
+                },
+                b if b.is_ascii_whitespace() && !quoted => break,
+                b => arg.push(b),
+            }
+        }
+        if quoted {
+            return Err("Unterminated quote in command".into());
+        }
+        match arg.strip_prefix(b"@") {
+            Some(path) if !was_quoted => {
+                let path = String::from_utf8_lossy(path).into_owned();
+                args.push(std::fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?);
+            }
+            _ => args.push(arg),
+        }
     }
 }
 
-async fn cmd_listener() {
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", util::CMD_PORT_BASE)).await.unwrap();
+/// Bytes of a key or value as an argument that `tokenize` reads back unchanged
+fn quote(bytes: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(b as char);
+            }
+            b'\n' => quoted.push_str("\\n"),
+            b'\t' => quoted.push_str("\\t"),
+            b' '..=b'~' => quoted.push(b as char),
+            b => quoted.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
 
-    while let Ok((mut socket, _)) = listener.accept().await {
-        let mut buffer = vec![0u8; 128];
-        while let Ok(n) = socket.read(&mut buffer).await {
-            if n == 0 {
-                break;
+fn print_response(message: util::ClientResponse) {
+    print!("[request {}] ", message.id);
+    match message.response {
+        util::Response::Value { value: Some(value), version, index } => {
+            println!("Received message: {} (version {}, index {})", quote(&value), version, index)
+        }
+        util::Response::Value { value: None, index, .. } => println!("Key not found at index {}", index),
+        util::Response::Scan { entries, next, index } => {
+            println!("{} entries at index {}", entries.len(), index);
+            for entry in entries {
+                println!("  {} -> {} (version {})", quote(&entry.key), quote(&entry.value), entry.version);
             }
-            let message: String = bincode::deserialize(&buffer[..n]).unwrap();
-            println!("Received message: {:?}", message);
-            buffer.clear();
+            if let Some(next) = next {
+                println!("  more entries, continue with token: {}", quote(&next));
+            }
+        }
+        util::Response::History { key, changes, truncated } => {
+            println!("{} changes to {}", changes.len(), quote(&key));
+            for change in changes {
+                match change.value {
+                    Some(value) => print!("  [{}] {}", change.index, quote(&value)),
+                    None => print!("  [{}] deleted", change.index),
+                }
+                println!(" by {} at {}", change.client, change.timestamp);
+            }
+            if truncated {
+                println!("  older changes have been discarded, the node keeps a limited number of changes per key");
+            }
+        }
+        util::Response::Watch(util::WatchEvent { key, change }) => match change.value {
+            Some(value) => println!("[{}] {} -> {} by {}", change.index, quote(&key), quote(&value), change.client),
+            None => println!("[{}] {} deleted by {}", change.index, quote(&key), change.client),
+        },
+        util::Response::Cas { key, success: true, .. } => println!("CAS on {} succeeded", quote(&key)),
+        util::Response::Cas { key, success: false, current } => {
+            let current = current.map_or_else(|| "none".to_string(), |current| quote(&current));
+            println!("CAS on {} failed, current value: {}", quote(&key), current)
+        }
+        util::Response::Committed => println!("Committed"),
+        util::Response::Transaction { committed: true, .. } => println!("Transaction committed"),
+        util::Response::Transaction { committed: false, failed } => {
+            println!("Transaction aborted, condition failed: {}", failed.unwrap_or_default())
         }
+        util::Response::Error(e) => println!("Request failed: {}", e),
+        util::Response::Timeout => println!("Request timed out before it was decided"),
+        util::Response::Redirect { leader } => {
+            println!("Gave up after {} redirects, last leader: {}", MAX_REDIRECTS, leader)
+        }
+        util::Response::Leader(Some(leader)) => println!("Leader is server {}", leader),
+        util::Response::Leader(None) => println!("No leader elected"),
+        util::Response::Status { status, applied, leader } => {
+            let leader = leader.map_or_else(|| "none".to_string(), |leader| leader.to_string());
+            println!("{:?} at index {}, leader: {}", status, applied, leader)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_splits_quotes_and_escapes() {
+        let args = tokenize(r#"  write "a key" x\"y\\z\n "" "\x00\xff\t" "#).unwrap();
+        let expected: Vec<&[u8]> = vec![b"write", b"a key", b"x\"y\\z\n", b"", b"\x00\xff\t"];
+        assert_eq!(args, expected);
+
+        assert!(tokenize(r#"write "open"#).is_err());
+        assert!(tokenize(r"write key \").is_err());
+        assert!(tokenize(r"write key \x4").is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn tokenize_reads_files() {
+        let path = std::env::temp_dir().join(format!("cli_client_test_{}", std::process::id()));
+        std::fs::write(&path, b"file\0contents").unwrap();
+        let args = tokenize(&format!("write key @{} \"@{}\"", path.display(), path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(args[2], b"file\0contents");
+        // a quoted argument is taken as is
+        assert_eq!(args[3], format!("@{}", path.display()).into_bytes());
+    }
+
+    #[test]
+    fn quote_round_trips() {
+        let every_byte: Vec<u8> = (0..=255).collect();
+        for bytes in [&b""[..], b"plain", b"with \"quotes\" and \\", b"line\nbreak\ttab", &every_byte] {
+            assert_eq!(tokenize(&quote(bytes)).unwrap(), vec![bytes.to_vec()]);
+        }
+    }
+}
@@ -1,67 +1,95 @@
+use structopt::StructOpt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::io;
 
+#[path="../cluster.rs"]
+mod cluster;
+#[path="../codec.rs"]
+mod codec;
 #[path="../util.rs"]
 mod util;
 
 #[tokio::main]
 async fn main() {
+    let opt = cluster::ClusterOpt::from_args();
+    let cluster = match cluster::Cluster::from_opt(&opt) {
+        Ok(cluster) => cluster,
+        Err(e) => {
+            eprintln!("Failed to load cluster configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Spawns a task to print output from the command window
-    tokio::spawn(man_listener());
+    tokio::spawn(man_listener(cluster.man_client()));
 
     println!("Management client started, waiting for commands");
 
-    // TODO move IP into config file?
-    let ip = "127.0.0.1";
-
     loop {
         let mut input = String::new();
-        std::io::stdin()
-            .read_line(&mut input)
-            .expect(&*format!("Failed to read input: {}", input));
+        match std::io::stdin().read_line(&mut input) {
+            Ok(0) => break,
+            Ok(..) => {}
+            Err(e) => {
+                eprintln!("Failed to read input: {}", e);
+                break;
+            }
+        }
 
-        let id: u64 = input
-            .split_whitespace()
-            .next()
-            .and_then(|s| s.parse().ok())
-            .expect(&*format!("Failed to read ID from input string: {}", input));
+        let (id, message) = match input.trim().split_once(' ').map(|(id, message)| (id.parse::<u64>(), message)) {
+            Some((Ok(id), message)) => (id, message.trim()),
+            _ => {
+                eprintln!("Failed to read ID from input string: {}", input);
+                continue;
+            }
+        };
 
         // TODO use HTTP requests?
-        let addr = format!("{}:{}", ip, util::MAN_PORT_BASE + id);
+        let addr = cluster.node(id).admin;
         println!("Sending message to manager on addr: {}", addr);
-        let stream = TcpStream::connect(addr).await.unwrap();
-        let mut writer = tokio::io::split(stream).1;
-
-        let message = input[2..].trim();
-        let message_bytes = bincode::serialize(&message).unwrap();
-
-        // println!("Serialized message: {:?}, original: {}", message_bytes, line[2..].trim());
-        // let dec: String = bincode::deserialize(&message_bytes).unwrap();
-        // println!("Deserialized message: {:?}", dec);
-
-        writer.write_all(&message_bytes).await.unwrap();
+        let mut stream = match TcpStream::connect(&addr).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to connect to manager on {}: {}", addr, e);
+                continue;
+            }
+        };
+        if let Err(e) = codec::write_message(&mut stream, &message).await {
+            eprintln!("Failed to send message to manager on {}: {}", addr, e);
+        }
     }
 }
 
-async fn man_listener() {
-    let ip = "127.0.0.1";
-    let port = util::MAN_CLIENT_PORT;
-    let addr = format!("{}:{}", ip, port);
-
-    let listener = TcpListener::bind(&addr).await.unwrap();
+async fn man_listener(addr: String) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind man_client listener on {}, replies won't be shown: {}", addr, e);
+            return;
+        }
+    };
 
     println!("Starting man_client listener on addr: {}", addr);
 
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
         let (mut reader, _) = io::split(socket);
-        let mut buffer = vec![1; 2048];
         loop {
-            let n = reader.read(&mut buffer).await.unwrap();
-            if n == 0 { break; }
-            let resized = &buffer[..n];
-            let msg: Vec<u8> = bincode::deserialize(resized).unwrap();
-            println!("Response received: {:?}", msg);
+            match codec::read_message::<_, Result<Vec<u64>, String>>(&mut reader).await {
+                Ok(Some(Ok(msg))) => println!("Response received: {:?}", msg),
+                Ok(Some(Err(e))) => println!("Error received: {}", e),
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Failed to read response: {}", e);
+                    break;
+                }
+            }
         }
     }
 }
\ No newline at end of file
@@ -19,8 +19,8 @@ import requests
 import random
 import sys
 
-def put_url(id, key, value):
-    return f"http://localhost:{9000 + id}/kv/{key}/{value}"
+def put_url(id, key):
+    return f"http://localhost:{9000 + id}/v1/kv/{key}"
 
 def get_url(id, key):
     return f"http://localhost:{9000 + id}/kv/{key}"
@@ -51,7 +51,7 @@ if __name__ == "__main__":
 
         # Write the key-value pair to one random node
         id = random.randint(1, num_nodes)
-        requests.get(put_url(id, key, id))
+        requests.put(put_url(id, key), data=str(id))
 
     write_time = time.time() - start_time
 
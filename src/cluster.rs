@@ -0,0 +1,94 @@
+//! Addresses of the nodes in the cluster, shared by the server and the clients.
+//!
+//! Nodes listed in the cluster file use the addresses given there, every other node falls back to
+//! the default port layout on `--host`, shifted by `--port-offset`.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::util;
+
+/// Command line flags describing where the cluster runs
+#[derive(Debug, StructOpt, Serialize, Deserialize)]
+pub struct ClusterOpt {
+    /// TOML file listing the addresses of the nodes
+    #[structopt(long, parse(from_os_str))]
+    pub cluster: Option<PathBuf>,
+    /// Host of the nodes that are not listed in the cluster file
+    #[structopt(long, default_value = "127.0.0.1")]
+    pub host: String,
+    /// Added to every default port, so several clusters can run on one host
+    #[structopt(long, default_value = "0")]
+    pub port_offset: u64,
+}
+
+/// Addresses a single node listens on
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeAddrs {
+    pub id: u64,
+    /// Omni-paxos messages from the other nodes
+    pub peer: String,
+    /// Commands from `cli_client`
+    pub client: String,
+    /// Commands from `man_client`
+    pub admin: String,
+    /// HTTP API
+    pub http: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Cluster {
+    #[serde(default)]
+    pub nodes: Vec<NodeAddrs>,
+    /// Address `man_client` listens on for replies from the managers
+    pub man_client: Option<String>,
+    #[serde(skip)]
+    host: String,
+    #[serde(skip)]
+    port_offset: u64,
+}
+
+impl Cluster {
+    pub fn from_opt(opt: &ClusterOpt) -> Result<Self, String> {
+        let cluster: Cluster = match &opt.cluster {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| format!("failed to read cluster file {}: {}", path.display(), e))?;
+                toml::from_str(&content)
+                    .map_err(|e| format!("failed to parse cluster file {}: {}", path.display(), e))?
+            }
+            None => Cluster::default(),
+        };
+        Ok(cluster.with_defaults(opt))
+    }
+
+    /// Use the host and port offset of `opt` for nodes without explicit addresses
+    pub fn with_defaults(mut self, opt: &ClusterOpt) -> Self {
+        self.host = opt.host.clone();
+        self.port_offset = opt.port_offset;
+        self
+    }
+
+    pub fn node(&self, id: u64) -> NodeAddrs {
+        match self.nodes.iter().find(|n| n.id == id) {
+            Some(node) => node.clone(),
+            None => NodeAddrs {
+                id,
+                peer: self.default_addr(util::SERV_PORT_BASE + id),
+                client: self.default_addr(util::CMD_PORT_BASE + id),
+                admin: self.default_addr(util::MAN_PORT_BASE + id),
+                http: self.default_addr(util::HTTP_PORT_BASE + id),
+            },
+        }
+    }
+
+    pub fn man_client(&self) -> String {
+        self.man_client.clone().unwrap_or_else(|| self.default_addr(util::MAN_CLIENT_PORT))
+    }
+
+    fn default_addr(&self, port: u64) -> String {
+        format!("{}:{}", self.host, port + self.port_offset)
+    }
+}
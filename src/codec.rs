@@ -0,0 +1,97 @@
+//! Length-prefixed framing shared by the server, the cli client and the management client.
+//!
+//! Every message is sent as one frame: a big-endian `u32` length, followed by that many bytes
+//! made up of a one byte protocol version and the bincode encoded message.
+
+use std::io::{Error, ErrorKind};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Upper bound on a frame, so a corrupt length prefix can't make us allocate gigabytes
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Write `payload` as a single frame
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    let len = payload.len() + 1;
+    if len > MAX_FRAME_LEN as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("frame of {} bytes is too large", len)));
+    }
+    let mut frame = Vec::with_capacity(4 + len);
+    frame.extend_from_slice(&(len as u32).to_be_bytes());
+    frame.push(PROTOCOL_VERSION);
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await
+}
+
+/// Read the payload of the next frame, `None` if the connection was closed between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, format!("invalid frame length {}", len)));
+    }
+    let mut frame = vec![0u8; len as usize];
+    reader.read_exact(&mut frame).await?;
+    if frame[0] != PROTOCOL_VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!("unsupported protocol version {}", frame[0])));
+    }
+    frame.remove(0);
+    Ok(Some(frame))
+}
+
+/// Serialize `message` and write it as a single frame
+pub async fn write_message<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> std::io::Result<()> {
+    let payload = bincode::serialize(message).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    write_frame(writer, &payload).await
+}
+
+/// Read and deserialize the next message, `None` if the connection was closed between frames
+pub async fn read_message<R: AsyncRead + Unpin, T: DeserializeOwned>(reader: &mut R) -> std::io::Result<Option<T>> {
+    match read_frame(reader).await? {
+        Some(payload) => bincode::deserialize(&payload)
+            .map(Some)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let mut buf = vec![];
+        write_frame(&mut buf, b"first").await.unwrap();
+        write_frame(&mut buf, b"").await.unwrap();
+        write_message(&mut buf, &(7u64, "third".to_string())).await.unwrap();
+
+        let mut reader = buf.as_slice();
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(vec![]));
+        assert_eq!(read_message::<_, (u64, String)>(&mut reader).await.unwrap(), Some((7, "third".to_string())));
+        // closed between frames
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_frames_are_rejected() {
+        let mut wrong_version = vec![0, 0, 0, 2, PROTOCOL_VERSION + 1, 0];
+        assert!(read_frame(&mut wrong_version.as_slice()).await.is_err());
+
+        wrong_version[..4].copy_from_slice(&(MAX_FRAME_LEN + 1).to_be_bytes());
+        assert!(read_frame(&mut wrong_version.as_slice()).await.is_err());
+
+        let mut truncated = vec![];
+        write_frame(&mut truncated, b"payload").await.unwrap();
+        truncated.pop();
+        assert!(read_frame(&mut truncated.as_slice()).await.is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Node configuration, read from the `--config` TOML file with command line flags taking precedence
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::cluster::{Cluster, ClusterOpt, NodeAddrs};
+
+const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 50;
+const DEFAULT_ELECTION_TIMEOUT_MS: u64 = 100;
+const DEFAULT_ELECTION_JITTER_MS: u64 = 20;
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 1000;
+
+#[derive(Debug, StructOpt, Serialize, Deserialize)]
+pub struct Node {
+    /// TOML configuration file, any flag given on the command line overrides its value
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(long)]
+    id: Option<u64>,
+    #[structopt(long)]
+    peers: Vec<u64>,
+    /// Refuse to start unless there is a persisted log to recover from
+    #[structopt(long)]
+    recover: bool,
+    /// Start without a configuration and wait to be added to the cluster with `add_node`
+    #[structopt(long)]
+    join: bool,
+    /// Directory of the persistent Omni-paxos storage, defaults to `./recv/node<ID>`
+    #[structopt(long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    /// How often the node does its periodic work, e.g. proposing the removal of expired keys
+    #[structopt(long)]
+    heartbeat_interval_ms: Option<u64>,
+    /// How often the Omni-paxos election timeout fires
+    #[structopt(long)]
+    election_timeout_ms: Option<u64>,
+    /// Upper bound of the random delay added to every election timeout
+    #[structopt(long)]
+    election_jitter_ms: Option<u64>,
+    /// Number of decided entries after which the log is compacted into a snapshot, 0 disables it
+    #[structopt(long)]
+    snapshot_interval: Option<u64>,
+    /// One of off, error, warn, info, debug, trace
+    #[structopt(long)]
+    log_level: Option<String>,
+    #[structopt(flatten)]
+    bind: BindOpt,
+    #[structopt(flatten)]
+    cluster: ClusterOpt,
+}
+
+/// Addresses this node binds to, when they differ from the ones the other nodes and clients use
+#[derive(Debug, Default, StructOpt, Serialize, Deserialize)]
+pub struct BindOpt {
+    #[structopt(long = "bind-peer")]
+    peer: Option<String>,
+    #[structopt(long = "bind-client")]
+    client: Option<String>,
+    #[structopt(long = "bind-admin")]
+    admin: Option<String>,
+    #[structopt(long = "bind-http")]
+    http: Option<String>,
+}
+
+/// Contents of the `--config` file, every key is optional
+#[derive(Debug, Default, Deserialize)]
+struct FileConfig {
+    id: Option<u64>,
+    peers: Option<Vec<u64>>,
+    data_dir: Option<PathBuf>,
+    heartbeat_interval_ms: Option<u64>,
+    election_timeout_ms: Option<u64>,
+    election_jitter_ms: Option<u64>,
+    snapshot_interval: Option<u64>,
+    log_level: Option<String>,
+    #[serde(default)]
+    bind: BindOpt,
+    /// Node addresses, used when no `--cluster` file is given
+    #[serde(flatten)]
+    cluster: Cluster,
+}
+
+/// Resolved configuration of this node
+#[derive(Debug)]
+pub struct NodeConfig {
+    pub id: u64,
+    pub peers: Vec<u64>,
+    pub join: bool,
+    pub recover: bool,
+    pub data_dir: PathBuf,
+    pub heartbeat_interval: Duration,
+    pub election_timeout: Duration,
+    pub election_jitter: Duration,
+    pub snapshot_interval: u64,
+    pub log_level: LevelFilter,
+    pub cluster: Cluster,
+    /// Addresses this node listens on
+    pub bind: NodeAddrs,
+}
+
+impl NodeConfig {
+    pub fn load(node: Node) -> Result<Self, String> {
+        let file: FileConfig = match &node.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| format!("failed to read config file {}: {}", path.display(), e))?;
+                toml::from_str(&content)
+                    .map_err(|e| format!("failed to parse config file {}: {}", path.display(), e))?
+            }
+            None => FileConfig::default(),
+        };
+        Self::resolve(node, file)
+    }
+
+    /// Combine the command line flags with the config file, flags taking precedence
+    fn resolve(node: Node, file: FileConfig) -> Result<Self, String> {
+        let id = node.id.or(file.id).ok_or("node id must be given with --id or in the config file")?;
+        // 0 stands for "no leader" in the leader announcements
+        if id == 0 {
+            return Err("node id must not be 0".into());
+        }
+
+        let cluster = match node.cluster.cluster {
+            Some(..) => Cluster::from_opt(&node.cluster)?,
+            None => file.cluster.with_defaults(&node.cluster),
+        };
+
+        // without explicit peers, every other node of the cluster file is a peer
+        let mut peers = if !node.peers.is_empty() {
+            node.peers
+        } else if let Some(peers) = file.peers {
+            peers
+        } else {
+            cluster.nodes.iter().map(|n| n.id).collect()
+        };
+        // a shared file may list this node too, e.g. when `--id` overrides the id in it
+        peers.retain(|peer| *peer != id);
+        peers.sort_unstable();
+        peers.dedup();
+        if peers.contains(&0) {
+            return Err("peer ids must not be 0".into());
+        }
+
+        let log_level = match node.log_level.or(file.log_level) {
+            Some(level) => level.parse().map_err(|_| format!("invalid log level: {}", level))?,
+            None => LevelFilter::Info,
+        };
+
+        let addrs = cluster.node(id);
+        let bind = NodeAddrs {
+            id,
+            peer: node.bind.peer.or(file.bind.peer).unwrap_or(addrs.peer),
+            client: node.bind.client.or(file.bind.client).unwrap_or(addrs.client),
+            admin: node.bind.admin.or(file.bind.admin).unwrap_or(addrs.admin),
+            http: node.bind.http.or(file.bind.http).unwrap_or(addrs.http),
+        };
+
+        let heartbeat_interval_ms =
+            node.heartbeat_interval_ms.or(file.heartbeat_interval_ms).unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_MS);
+        let election_timeout_ms = node.election_timeout_ms.or(file.election_timeout_ms).unwrap_or(DEFAULT_ELECTION_TIMEOUT_MS);
+        if heartbeat_interval_ms == 0 || election_timeout_ms == 0 {
+            return Err("heartbeat interval and election timeout must be at least 1 ms".into());
+        }
+
+        Ok(NodeConfig {
+            id,
+            peers,
+            join: node.join,
+            recover: node.recover,
+            data_dir: node.data_dir.or(file.data_dir).unwrap_or_else(|| PathBuf::from(format!("./recv/node{}", id))),
+            heartbeat_interval: Duration::from_millis(heartbeat_interval_ms),
+            election_timeout: Duration::from_millis(election_timeout_ms),
+            election_jitter: Duration::from_millis(
+                node.election_jitter_ms.or(file.election_jitter_ms).unwrap_or(DEFAULT_ELECTION_JITTER_MS),
+            ),
+            snapshot_interval: node.snapshot_interval.or(file.snapshot_interval).unwrap_or(DEFAULT_SNAPSHOT_INTERVAL),
+            log_level,
+            cluster,
+            bind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+id = 1
+peers = [1, 2, 3, 3]
+data_dir = "./file/node1"
+snapshot_interval = 10
+
+[bind]
+http = "0.0.0.0:9001"
+
+[[nodes]]
+id = 2
+peer = "10.0.0.2:50002"
+client = "10.0.0.2:61002"
+admin = "10.0.0.2:60002"
+http = "10.0.0.2:9002"
+
+[[nodes]]
+id = 3
+peer = "10.0.0.3:50003"
+client = "10.0.0.3:61003"
+admin = "10.0.0.3:60003"
+http = "10.0.0.3:9003"
+"#;
+
+    fn resolve(args: &[&str], file: &str) -> Result<NodeConfig, String> {
+        let node = Node::from_iter_safe(std::iter::once("kv_store").chain(args.iter().copied()))
+            .map_err(|e| e.to_string())?;
+        NodeConfig::resolve(node, toml::from_str(file).map_err(|e| e.to_string())?)
+    }
+
+    #[test]
+    fn file_values_are_used_without_flags() {
+        let config = resolve(&[], FILE).unwrap();
+        assert_eq!(config.id, 1);
+        // the node's own id and duplicates are left out
+        assert_eq!(config.peers, vec![2, 3]);
+        assert_eq!(config.data_dir, PathBuf::from("./file/node1"));
+        assert_eq!(config.snapshot_interval, 10);
+        assert_eq!(config.bind.http, "0.0.0.0:9001");
+        // not listed in the file, so the default address of node 1
+        assert_eq!(config.bind.peer, "127.0.0.1:50001");
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let args = ["--id", "2", "--data-dir", "./flag/node2", "--bind-peer", "0.0.0.0:50002"];
+        let config = resolve(&args, FILE).unwrap();
+        assert_eq!(config.id, 2);
+        assert_eq!(config.peers, vec![1, 3]);
+        assert_eq!(config.data_dir, PathBuf::from("./flag/node2"));
+        assert_eq!(config.bind.peer, "0.0.0.0:50002");
+        assert_eq!(config.bind.client, "10.0.0.2:61002");
+        assert_eq!(config.bind.http, "0.0.0.0:9001");
+
+        let config = resolve(&["--peers", "3", "--peers", "2", "--peers", "3"], FILE).unwrap();
+        assert_eq!(config.peers, vec![2, 3]);
+    }
+
+    #[test]
+    fn defaults_without_file_values() {
+        let file = FILE.replace("peers = [1, 2, 3, 3]\n", "").replace("data_dir = \"./file/node1\"\n", "");
+        let config = resolve(&["--id", "3"], &file).unwrap();
+        // every other node of the cluster file
+        assert_eq!(config.peers, vec![2]);
+        assert_eq!(config.data_dir, PathBuf::from("./recv/node3"));
+        assert_eq!(config.snapshot_interval, 10);
+        assert_eq!(config.bind.peer, "10.0.0.3:50003");
+        assert_eq!(config.heartbeat_interval, Duration::from_millis(DEFAULT_HEARTBEAT_INTERVAL_MS));
+
+        let config = resolve(&["--id", "3"], "").unwrap();
+        assert!(config.peers.is_empty());
+        assert_eq!(config.snapshot_interval, DEFAULT_SNAPSHOT_INTERVAL);
+    }
+
+    #[test]
+    fn invalid_ids_are_rejected() {
+        assert!(resolve(&[], "").is_err());
+        assert!(resolve(&["--id", "0"], "").is_err());
+        assert!(resolve(&["--peers", "0"], FILE).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+//! Errors raised while serving peers and clients. None of them stop the node: they are logged, and
+//! returned to the client that caused them where there is one.
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A message from a peer or client could not be decoded
+    Decode(bincode::Error),
+    /// A message could not be encoded
+    Encode(bincode::Error),
+    /// Reading from or writing to a connection failed
+    Io(std::io::Error),
+    /// A management command could not be parsed
+    InvalidCommand(String),
+    /// The task a message was meant for has stopped
+    ChannelClosed(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decode(e) => write!(f, "failed to decode message: {}", e),
+            Error::Encode(e) => write!(f, "failed to encode message: {}", e),
+            Error::Io(e) => write!(f, "connection error: {}", e),
+            Error::InvalidCommand(e) => write!(f, "invalid command: {}", e),
+            Error::ChannelClosed(task) => write!(f, "{} task has stopped", task),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    bincode::deserialize(bytes).map_err(Error::Decode)
+}
+
+pub fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    bincode::serialize(message).map_err(Error::Encode)
+}
@@ -1,18 +1,36 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 
-use axum::extract::{Path, State};
-use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, OriginalUri, Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Redirect};
+use axum::{Json, Router};
 use axum::routing::get;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{debug, error, info};
+use serde::Deserialize;
+use serde_json::json;
 use tokio::sync::{mpsc, Mutex};
 
-use crate::KeyValue;
-use crate::store::KVStore;
+use crate::{Command, Condition, KeyValue, Write};
+use crate::cluster::Cluster;
+use crate::leader::LeaderInfo;
+use crate::pending;
+use crate::status::NodeStatus;
+use crate::store::{self, KVStore};
+use crate::util::{Response, Session, Status, WatchEvent};
+use crate::watch::{Watched, Watchers};
 
 struct HandlerData {
     kv_store: Arc<Mutex<KVStore>>,
-    sender: mpsc::Sender<(String, Vec<u8>)>,
+    leader: Arc<LeaderInfo>,
+    watchers: Arc<Watchers>,
+    status: Arc<NodeStatus>,
+    cluster: Cluster,
+    sender: mpsc::Sender<crate::Event>,
 }
 
 type ServerState = Arc<Mutex<HandlerData>>;
@@ -20,72 +38,516 @@ type ServerState = Arc<Mutex<HandlerData>>;
 async fn hello_world(State(state): State<ServerState>) -> String {
     let mut s = String::new();
     s.push_str("[ \n");
-    for (key, value) in &state.lock().await.kv_store.lock().await.clone() {
-        s.push_str(&*format!("\t{} -> {}, \n", key, value));
+    for (key, value) in state.lock().await.kv_store.lock().await.iter() {
+        s.push_str(&format!("\t{} -> {}, \n", String::from_utf8_lossy(key), String::from_utf8_lossy(value)));
     }
     s.push_str("]");
     s
 }
 
-#[axum_macros::debug_handler]
-async fn put_kv(
+async fn delete_kv(
+    State(state): State<ServerState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    Path(key): Path<String>,
+) -> axum::response::Response {
+    if let Some(redirect) = leader_redirect(&state, &uri).await {
+        return redirect;
+    }
+    // send tombstone to omnipaxos and wait until it is decided
+    match propose(&state, client, None, Command::Delete(key.clone().into_bytes())).await {
+        Response::Committed => format!("Deleted {}", key),
+        other => format!("Failed to delete {}: {:?}", key, other),
+    }.into_response()
+}
+
+async fn get_leader(State(state): State<ServerState>) -> String {
+    let data = state.lock().await;
+    match data.leader.get() {
+        Some(leader) => format!("{} -> {}", leader, data.cluster.node(leader).http),
+        None => "No leader elected".into(),
+    }
+}
+
+/// Temporary redirect to the same URI on the leader, for requests that go through the log while
+/// another node is leading. 307 keeps the method, so clients following it repeat the same request.
+async fn leader_redirect(state: &ServerState, uri: &Uri) -> Option<axum::response::Response> {
+    let data = state.lock().await;
+    let leader = data.leader.redirect()?;
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = format!("http://{}{}", data.cluster.node(leader).http, path);
+    debug!("Redirecting {} to leader {}", path, leader);
+    Some(Redirect::temporary(&location).into_response())
+}
+
+/// Propose a command without holding the state lock while waiting for it to be decided
+async fn propose(state: &ServerState, client: SocketAddr, session: Option<Session>, command: Command) -> Response {
+    let sender = state.lock().await.sender.clone();
+    pending::propose(&sender, format!("http:{}", client), session, command).await
+}
+
+#[derive(Deserialize)]
+struct ReadParams {
+    /// `local` (default) serves from this node's store, `linearizable` reads through the log
+    consistency: Option<String>,
+    /// Serve the read from this node's store as of a past log index
+    at: Option<u64>,
+}
+
+async fn get_kv(
     State(state): State<ServerState>,
-    Path(params): Path<HashMap<String, String>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    Path(key): Path<String>,
+    Query(params): Query<ReadParams>,
+) -> axum::response::Response {
+    let response = match (params.consistency.as_deref(), params.at) {
+        (Some("linearizable"), None) => {
+            if let Some(redirect) = leader_redirect(&state, &uri).await {
+                return redirect;
+            }
+            propose(&state, client, None, Command::Read(key.clone().into_bytes())).await
+        }
+        (None, at) | (Some("local"), at) => state.lock().await.kv_store.lock().await.read(key.as_bytes(), at),
+        (Some("linearizable"), Some(..)) => return "Linearizable reads can't be served at an index".into_response(),
+        (Some(other), _) => return format!("Unknown consistency level: {}", other).into_response(),
+    };
+    match response {
+        Response::Value { value: Some(val), version, index } => {
+            format!("{} -> {} (version {}, index {})", key, String::from_utf8_lossy(&val), version, index)
+        }
+        Response::Value { value: None, index, .. } => format!("No value for key {} found at index {}", key, index),
+        other => format!("Failed to read {}: {:?}", key, other),
+    }.into_response()
+}
+
+#[derive(Deserialize)]
+struct HistoryParams {
+    limit: Option<usize>,
+}
+
+async fn get_history(
+    State(state): State<ServerState>,
+    Path(key): Path<String>,
+    Query(params): Query<HistoryParams>,
 ) -> String {
-    let key= params.get("key").unwrap().to_owned();
-    let value = match
-        params.get("value") {
-        Some(value) => value.clone(),
-        None => {
-            eprintln!("No value found");
-            return "No value found".into();
+    let limit = params.limit.unwrap_or(store::DEFAULT_HISTORY_LIMIT);
+    let response = state.lock().await.kv_store.lock().await.history(key.as_bytes(), limit);
+    let (changes, truncated) = match response {
+        Response::History { changes, truncated, .. } => (changes, truncated),
+        other => return format!("Failed to read history of {}: {:?}", key, other),
+    };
+    let mut s = String::new();
+    for change in changes {
+        let value = match change.value {
+            Some(value) => format!("{} -> {}", key, String::from_utf8_lossy(&value)),
+            None => format!("{} deleted", key),
+        };
+        s.push_str(&format!("[{}] {} by {} at {}\n", change.index, value, change.client, change.timestamp));
+    }
+    if truncated {
+        s.push_str("older changes have been discarded, the node keeps a limited number of changes per key\n");
+    }
+    s
+}
+
+#[derive(Deserialize)]
+struct WatchParams {
+    /// Watch a single key, or every key starting with `prefix`
+    key: Option<String>,
+    prefix: Option<String>,
+    /// Replay the changes decided from this log index on first
+    from: Option<u64>,
+}
+
+/// JSON view of a watched change, with keys and values both as text and base64
+fn event_json(event: &WatchEvent) -> serde_json::Value {
+    let value = event.change.value.as_deref();
+    json!({
+        "key": text(&event.key),
+        "key_base64": BASE64.encode(&event.key),
+        "index": event.change.index,
+        "value": value.and_then(text),
+        "value_base64": value.map(|value| BASE64.encode(value)),
+        "client": event.change.client,
+        "timestamp": event.change.timestamp,
+    })
+}
+
+/// Stream the changes of a key or prefix as server-sent events, with the log index as event id
+async fn watch_kv(State(state): State<ServerState>, Query(params): Query<WatchParams>) -> axum::response::Response {
+    let watched = match (params.key, params.prefix) {
+        (Some(key), None) => Watched::Key(key.into_bytes()),
+        (None, Some(prefix)) => Watched::Prefix(prefix.into_bytes()),
+        _ => return "Expected either ?key=<KEY> or ?prefix=<PREFIX>".into_response(),
+    };
+    let (kv_store, watchers) = {
+        let data = state.lock().await;
+        (Arc::clone(&data.kv_store), Arc::clone(&data.watchers))
+    };
+    let events = match watchers.watch(&kv_store, watched, params.from).await {
+        Ok(events) => events,
+        Err(e) => return format!("Failed to watch: {}", e).into_response(),
+    };
+    let stream = futures::stream::unfold(events, |mut events| async move {
+        let event = match events.recv().await? {
+            Ok(event) => Event::default().id(event.change.index.to_string()).json_data(event_json(&event)),
+            Err(e) => Ok(Event::default().event("error").data(e)),
+        };
+        Some((event, events))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
+
+#[derive(Deserialize)]
+struct ScanParams {
+    /// List keys starting with `prefix`, or else keys in `[start, end)`
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    limit: Option<usize>,
+    /// `next` token returned with the previous page
+    token: Option<String>,
+    /// Scan the store as of a past log index
+    at: Option<u64>,
+}
+
+async fn scan_kv(State(state): State<ServerState>, Query(params): Query<ScanParams>) -> String {
+    let limit = params.limit.unwrap_or(store::DEFAULT_SCAN_LIMIT);
+    let token = params.token.as_deref().map(str::as_bytes);
+    let response = {
+        let data = state.lock().await;
+        let kv_store = data.kv_store.lock().await;
+        match (&params.prefix, &params.start, &params.end) {
+            (Some(prefix), None, None) => store::prefix(&kv_store, prefix.as_bytes(), limit, token, params.at),
+            (None, Some(start), Some(end)) => {
+                store::scan(&kv_store, start.as_bytes(), end.as_bytes(), limit, token, params.at)
+            }
+            _ => return "Expected either ?prefix=<PREFIX> or ?start=<START>&end=<END>".into(),
         }
     };
+    let (entries, next, index) = match response {
+        Response::Scan { entries, next, index } => (entries, next, index),
+        other => return format!("Failed to scan: {:?}", other),
+    };
+    let mut s = format!("index: {}\n", index);
+    for entry in entries {
+        let (key, value) = (String::from_utf8_lossy(&entry.key), String::from_utf8_lossy(&entry.value));
+        s.push_str(&format!("{} -> {} (version {})\n", key, value, entry.version));
+    }
+    if let Some(next) = next {
+        s.push_str(&format!("next: {}\n", String::from_utf8_lossy(&next)));
+    }
+    s
+}
 
-    // create key value
-    let kv = KeyValue { key: key.to_string(), value: value.clone() };
+/// Error object returned by the `/v1` API
+fn json_error(status: StatusCode, message: impl Into<String>) -> axum::response::Response {
+    (status, Json(json!({ "error": message.into() }))).into_response()
+}
 
-    // send to omnipaxos
-    state.lock().await.sender.send((
-            "write".into(),
-            bincode::serialize(&kv).unwrap())
-        ).await.unwrap();
+/// Bytes as a JSON string if they are valid UTF-8
+fn text(bytes: &[u8]) -> Option<&str> {
+    std::str::from_utf8(bytes).ok()
+}
 
-    // return ok
-    format!("Inserted ({}, {})", kv.key, kv.value)
+/// Key of a `/v1` request, taken from the path as text or, with `?encoding=base64`, as base64
+fn v1_key(key: String, encoding: Option<&str>) -> Result<Vec<u8>, axum::response::Response> {
+    match encoding {
+        None | Some("text") => Ok(key.into_bytes()),
+        Some("base64") => BASE64
+            .decode(&key)
+            .map_err(|e| json_error(StatusCode::BAD_REQUEST, format!("key is not valid base64: {}", e))),
+        Some(other) => Err(json_error(StatusCode::BAD_REQUEST, format!("unknown key encoding: {}", other))),
+    }
 }
 
-async fn get_kv(State(state): State<ServerState>, Path(key): Path<String>) -> String {
-    match &state.lock().await.kv_store.lock().await.get(key.as_str()) {
-        Some(val) => format!("{} -> {}", key, val),
-        None => format!("No value for key {} found", key)
+/// Session of a `/v1` write from the `X-Client-Id` and `X-Request-Seq` headers, a retry with the
+/// same headers gets the result of the original request
+fn v1_session(headers: &HeaderMap) -> Result<Option<Session>, axum::response::Response> {
+    let header = |name: &str| {
+        headers.get(name).map(|value| {
+            value.to_str().ok().and_then(|value| value.parse::<u64>().ok()).ok_or_else(|| {
+                json_error(StatusCode::BAD_REQUEST, format!("{} expects a number", name))
+            })
+        })
+    };
+    match (header("x-client-id"), header("x-request-seq")) {
+        (Some(client), Some(seq)) => Ok(Some(Session { client: client?, seq: seq? })),
+        (None, None) => Ok(None),
+        _ => Err(json_error(StatusCode::BAD_REQUEST, "X-Client-Id and X-Request-Seq must be given together")),
     }
 }
 
-pub async fn api_server(kv_store: Arc<Mutex<KVStore>>, sender: mpsc::Sender<(String, Vec<u8>)>, id: &u64) {
+/// Whether the client asked for the raw value rather than a JSON object
+fn wants_raw(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.starts_with("application/octet-stream"))
+}
+
+/// Map the response to a `/v1` request to its status code and JSON body. `error_status` is used for
+/// `Response::Error`, which is a bad request for local reads and an unavailable cluster for proposals.
+/// Keys and values are given as text when they are valid UTF-8 and always as base64.
+fn v1_response(key: &[u8], response: Response, error_status: StatusCode) -> axum::response::Response {
+    match response {
+        Response::Value { value: Some(value), version, index } => Json(json!({
+            "key": text(key),
+            "key_base64": BASE64.encode(key),
+            "value": text(&value),
+            "value_base64": BASE64.encode(&value),
+            "version": version,
+            "index": index,
+        }))
+        .into_response(),
+        Response::Value { value: None, index, .. } => json_error(
+            StatusCode::NOT_FOUND,
+            format!("key {} not found at index {}", String::from_utf8_lossy(key), index),
+        ),
+        Response::Committed | Response::Transaction { committed: true, .. } => {
+            Json(json!({ "key": text(key), "key_base64": BASE64.encode(key) })).into_response()
+        }
+        Response::Transaction { committed: false, failed } => {
+            json_error(StatusCode::CONFLICT, format!("condition failed: {}", failed.unwrap_or_default()))
+        }
+        Response::Timeout => json_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "request was not decided in time, it may or may not still take effect",
+        ),
+        Response::Error(e) => json_error(error_status, e),
+        other => json_error(StatusCode::INTERNAL_SERVER_ERROR, format!("unexpected response: {:?}", other)),
+    }
+}
+
+/// Proposes a write, conditioned on the version of the key if the client asked for it
+fn v1_write(key: Vec<u8>, write: Write, version: Option<u64>) -> Command {
+    match (version, write) {
+        (None, Write::Put(kv)) => Command::Put(kv),
+        (None, Write::Delete(key)) => Command::Delete(key),
+        (Some(version), write) => {
+            Command::Transaction { conditions: vec![Condition::Version { key, version }], writes: vec![write] }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct V1ReadParams {
+    /// Same as for `/kv`, not flattened from `ReadParams` since flattened query values can't be
+    /// parsed as numbers
+    consistency: Option<String>,
+    at: Option<u64>,
+    /// `base64` if the key in the path is base64 encoded
+    encoding: Option<String>,
+}
+
+/// Read a key. With `Accept: application/octet-stream` the value is returned as the raw body, with
+/// its version and read index in the `X-Version` and `X-Index` headers.
+async fn v1_get(
+    State(state): State<ServerState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    Path(key): Path<String>,
+    Query(params): Query<V1ReadParams>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let key = match v1_key(key, params.encoding.as_deref()) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let (response, error_status) = match (params.consistency.as_deref(), params.at) {
+        (Some("linearizable"), None) => {
+            if let Some(redirect) = leader_redirect(&state, &uri).await {
+                return redirect;
+            }
+            (propose(&state, client, None, Command::Read(key.clone())).await, StatusCode::SERVICE_UNAVAILABLE)
+        }
+        (None, at) | (Some("local"), at) => {
+            (state.lock().await.kv_store.lock().await.read(&key, at), StatusCode::BAD_REQUEST)
+        }
+        (Some("linearizable"), Some(..)) => {
+            return json_error(StatusCode::BAD_REQUEST, "linearizable reads can't be served at an index")
+        }
+        (Some(other), _) => {
+            return json_error(StatusCode::BAD_REQUEST, format!("unknown consistency level: {}", other))
+        }
+    };
+    match response {
+        Response::Value { value: Some(value), version, index } if wants_raw(&headers) => {
+            let headers = [
+                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                (header::HeaderName::from_static("x-version"), version.to_string()),
+                (header::HeaderName::from_static("x-index"), index.to_string()),
+            ];
+            (headers, value).into_response()
+        }
+        response => v1_response(&key, response, error_status),
+    }
+}
+
+/// JSON body of `PUT /v1/kv/{key}`, any other body is taken as the raw value
+#[derive(Deserialize)]
+struct PutBody {
+    /// Value as text, or as base64 in `value_base64`
+    value: Option<String>,
+    value_base64: Option<String>,
+    /// Seconds until the key expires
+    ttl: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct V1WriteParams {
+    /// Seconds until the key expires, for raw bodies
+    ttl: Option<u64>,
+    /// Only write if the key is at this version, 0 if it must not exist
+    version: Option<u64>,
+    /// `base64` if the key in the path is base64 encoded
+    encoding: Option<String>,
+}
+
+#[axum_macros::debug_handler]
+async fn v1_put(
+    State(state): State<ServerState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    Path(key): Path<String>,
+    Query(params): Query<V1WriteParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> axum::response::Response {
+    let json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    let (key, session) = match (v1_key(key, params.encoding.as_deref()), v1_session(&headers)) {
+        (Ok(key), Ok(session)) => (key, session),
+        (Err(response), _) | (_, Err(response)) => return response,
+    };
+    let (value, ttl) = if json {
+        let body = match serde_json::from_slice::<PutBody>(&body) {
+            Ok(body) => body,
+            Err(e) => return json_error(StatusCode::BAD_REQUEST, format!("invalid JSON body: {}", e)),
+        };
+        let value = match (body.value, body.value_base64) {
+            (Some(value), None) => value.into_bytes(),
+            (None, Some(value)) => match BASE64.decode(value) {
+                Ok(value) => value,
+                Err(e) => return json_error(StatusCode::BAD_REQUEST, format!("value is not valid base64: {}", e)),
+            },
+            _ => return json_error(StatusCode::BAD_REQUEST, "expected either value or value_base64"),
+        };
+        (value, body.ttl.or(params.ttl))
+    } else {
+        (body.to_vec(), params.ttl)
+    };
+    if let Some(redirect) = leader_redirect(&state, &uri).await {
+        return redirect;
+    }
+
+    let expires = match ttl.map(pending::expiry) {
+        None => None,
+        Some(Some(expires)) => Some(expires),
+        Some(None) => return json_error(StatusCode::BAD_REQUEST, "ttl is too large"),
+    };
+    let write = Write::Put(KeyValue { key: key.clone(), value, expires });
+    let response = propose(&state, client, session, v1_write(key.clone(), write, params.version)).await;
+    v1_response(&key, response, StatusCode::SERVICE_UNAVAILABLE)
+}
+
+async fn v1_delete(
+    State(state): State<ServerState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    Path(key): Path<String>,
+    Query(params): Query<V1WriteParams>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let (key, session) = match (v1_key(key, params.encoding.as_deref()), v1_session(&headers)) {
+        (Ok(key), Ok(session)) => (key, session),
+        (Err(response), _) | (_, Err(response)) => return response,
+    };
+    if let Some(redirect) = leader_redirect(&state, &uri).await {
+        return redirect;
+    }
+    let write = Write::Delete(key.clone());
+    let response = propose(&state, client, session, v1_write(key.clone(), write, params.version)).await;
+    v1_response(&key, response, StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// Status of the node, `503` while it is recovering so load balancers can hold off sending it requests
+async fn v1_status(State(state): State<ServerState>) -> axum::response::Response {
+    let data = state.lock().await;
+    let applied = data.kv_store.lock().await.applied();
+    let (code, status) = match data.status.get() {
+        Status::Recovering => (StatusCode::SERVICE_UNAVAILABLE, "recovering"),
+        Status::Ready => (StatusCode::OK, "ready"),
+    };
+    (code, Json(json!({ "status": status, "applied": applied, "leader": data.leader.get() }))).into_response()
+}
+
+pub async fn api_server(
+    kv_store: Arc<Mutex<KVStore>>,
+    leader: Arc<LeaderInfo>,
+    watchers: Arc<Watchers>,
+    status: Arc<NodeStatus>,
+    cluster: Cluster,
+    sender: mpsc::Sender<crate::Event>,
+    listen_addr: String,
+) {
     // let kv_store: HashMap<String, u64> = HashMap::new();
 
     let state: ServerState = Arc::new( Mutex::new(HandlerData {
         kv_store,
+        leader,
+        watchers,
+        status,
+        cluster,
         sender,
     }));
 
-    println!("Registering routes");
+    debug!("Registering routes");
     let app = Router::new()
         .route("/", get(hello_world))
-        .route("/kv/:key/:value", get(put_kv))
-        .route("/kv/:key", get(get_kv))
+        .route("/leader", get(get_leader))
+        .route("/kv", get(scan_kv))
+        .route("/kv/:key", get(get_kv).delete(delete_kv))
+        .route("/history/:key", get(get_history))
+        .route("/watch", get(watch_kv))
+        .route("/v1/kv/*key", get(v1_get).put(v1_put).delete(v1_delete))
+        .route("/v1/status", get(v1_status))
         .with_state(state);
 
-    // have to convert id to u16 since SocketAddr doesn't accept u64
-    // portn will be 0 if id > u16::max_value()
-    let portn = id.to_owned() as u16;
+    let addr = match listen_addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+        Some(addr) => addr,
+        None => {
+            error!("Failed to resolve HTTP address: {}", listen_addr);
+            return;
+        }
+    };
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 9000 + portn));
+    info!("Starting server on {}", addr);
+    let server = match axum::Server::try_bind(&addr) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Failed to bind HTTP server on {}: {}", addr, e);
+            return;
+        }
+    };
+    if let Err(e) = server.serve(app.into_make_service_with_connect_info::<SocketAddr>()).await {
+        error!("HTTP server stopped: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    println!("Starting server on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    #[test]
+    fn v1_read_params_parse_index() {
+        let uri: Uri = "/v1/kv/a2V5?at=5&encoding=base64".parse().unwrap();
+        let Query(params) = Query::<V1ReadParams>::try_from_uri(&uri).unwrap();
+        assert_eq!(params.at, Some(5));
+        assert_eq!(params.consistency, None);
+        assert_eq!(params.encoding.as_deref(), Some("base64"));
+    }
 }
@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Leader of the current configuration as last seen by the Omni-paxos task, shared with the tasks
+/// serving clients. Node ids start at 1, so 0 stands for no known leader.
+pub struct LeaderInfo {
+    id: u64,
+    leader: AtomicU64,
+}
+
+impl LeaderInfo {
+    pub fn new(id: u64) -> Self {
+        LeaderInfo { id, leader: AtomicU64::new(0) }
+    }
+
+    pub fn set(&self, leader: Option<u64>) {
+        self.leader.store(leader.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        match self.leader.load(Ordering::Relaxed) {
+            0 => None,
+            leader => Some(leader),
+        }
+    }
+
+    /// Node a client request should be redirected to, `None` if this node handles it itself
+    pub fn redirect(&self) -> Option<u64> {
+        self.get().filter(|leader| *leader != self.id)
+    }
+}
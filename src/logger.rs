@@ -0,0 +1,30 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Prints log records to stdout, warnings and errors to stderr
+struct StdLogger;
+
+impl Log for StdLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error | Level::Warn => eprintln!("[{}] {}", record.level(), record.args()),
+            _ => println!("[{}] {}", record.level(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StdLogger = StdLogger;
+
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
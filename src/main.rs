@@ -1,141 +1,299 @@
-use std::{path::Path, thread, time};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
 
-use commitlog::LogOptions;
+use log::{debug, error, info, warn};
 use omnipaxos_core::{
     omni_paxos::*,
-    util::LogEntry::Decided
+    util::LogEntry::{Decided, Snapshotted, Trimmed}
 };
 use omnipaxos_core::messages::Message;
 use omnipaxos_core::util::LogEntry;
 use omnipaxos_storage::{
-    persistent_storage::{PersistentStorage, PersistentStorageConfig},
+    persistent_storage::PersistentStorage,
 };
 use serde::{Deserialize, Serialize};
-use sled::Config;
 use structopt::StructOpt;
-use tokio::io::{split, WriteHalf};
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::io;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, oneshot, Mutex};
 
-use store::KVStore;
+use pending::PendingRequests;
+use config::{Node, NodeConfig};
+use error::Error;
+use leader::LeaderInfo;
+use management::AdminCommand;
+use membership::Membership;
+use status::NodeStatus;
+use store::{KVSnapshot, KVStore};
+use transport::PeerTransport;
+use util::{ClientRequest, ClientResponse, Response, Session};
+use watch::{Watched, Watchers};
 
+mod cluster;
+mod codec;
+mod config;
+mod error;
+mod logger;
 mod management;
+mod membership;
+mod pending;
+mod session;
+mod status;
+mod timer;
 mod util;
 mod http;
+mod leader;
 mod store;
+mod transport;
+mod watch;
 
-#[derive(Debug, StructOpt, Serialize, Deserialize)]
-struct Node {
-    #[structopt(long)]
-    id: u64,
-    #[structopt(long)]
-    peers: Vec<u64>,
-    #[structopt(parse(try_from_str), default_value = "false")]
-    recover: bool,
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyValue {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// When the key expires, in milliseconds since the Unix epoch. Fixed by the proposing node so
+    /// every replica expires the key at the same point.
+    pub expires: Option<u64>,
 }
 
+/// Operation replicated through the Omni-paxos log
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct KeyValue {
-    pub key: String,
-    pub value: String,
+pub enum Command {
+    Put(KeyValue),
+    /// Tombstone for a key, removes it from the store once decided
+    Delete(Vec<u8>),
+    /// Compare-and-swap, evaluated against the store when decided
+    Cas { key: Vec<u8>, expected: Vec<u8>, new: Vec<u8> },
+    /// No-op marker for a linearizable read, the key is read when the marker is decided
+    Read(Vec<u8>),
+    /// Keys whose time-to-live has passed, proposed by the leader. A key is only removed if it
+    /// still holds the value with the given expiry time.
+    Expire(Vec<(Vec<u8>, u64)>),
+    /// State of the previous configuration, the first entry of a new configuration's log
+    Install(KVSnapshot),
+    /// Writes applied all-or-nothing, only if every condition holds when the entry is decided
+    Transaction { conditions: Vec<Condition>, writes: Vec<Write> },
+}
+
+/// Precondition of a transaction
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Condition {
+    /// The key currently holds the value
+    Equals { key: Vec<u8>, value: Vec<u8> },
+    /// The key exists and was last written by the entry at log index `version`, 0 if the key
+    /// must not exist
+    Version { key: Vec<u8>, version: u64 },
+}
+
+/// Write of a transaction
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Write {
+    Put(KeyValue),
+    Delete(Vec<u8>),
+}
+
+/// Identifies a client request by the node that proposed it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RequestId {
+    pub node: u64,
+    pub seq: u64,
+}
+
+/// Entry of the Omni-paxos log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Proposal {
+    pub id: RequestId,
+    /// Client that issued the request, the id of its session or else the address of its connection
+    pub client: String,
+    /// When the request was proposed, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Client session of the request, entries proposed by the nodes themselves have none
+    pub session: Option<Session>,
+    pub command: Command,
+}
+
+/// How often the leader looks for expired keys
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the installer waits for the state of the previous configuration to be installed before
+/// it proposes it again, doubled after every retry up to `MAX_INSTALL_RETRY_INTERVAL`
+const INSTALL_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_INSTALL_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Largest encoded state a reconfiguration moves in its `Install` entry. Omni-paxos sends the entry
+/// in a single peer frame, possibly batched with others, so it may only take half of one.
+const MAX_INSTALL_LEN: u64 = codec::MAX_FRAME_LEN as u64 / 2;
+
+type OmniPaxosKV = OmniPaxos<Proposal, KVSnapshot, PersistentStorage<Proposal, KVSnapshot>>;
+
+/// Frame exchanged between nodes on the peer connections
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum PeerMessage {
+    /// Omni-paxos message of the configuration `config_id`
+    OmniPaxos { config_id: u32, message: Message<Proposal, KVSnapshot> },
+    /// A new configuration was decided, sent to its members so joining nodes can start it
+    Reconfigured(Membership),
+}
+
+/// Input of the Omni-paxos task, the only task driving this node's Omni-paxos instance
+#[derive(Debug)]
+enum Event {
+    /// Message received from another node
+    PeerMessage(PeerMessage),
+    /// Client request to propose, answered on `reply` once its entry has been decided
+    ClientRequest { client: String, session: Option<Session>, command: Command, reply: oneshot::Sender<Response> },
+    Tick(Tick),
+    /// Command of the management client
+    Admin(AdminCommand),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Tick {
+    /// Periodic work of the node, the leader proposes the removal of expired keys
+    Heartbeat,
+    /// Drive the Omni-paxos leader election
+    Election,
 }
 
 
 #[tokio::main]
 async fn main() {
-    let node = Node::from_args();
-    let node_id = node.id;
-    let recover_path = format!("./recv/node{}", node_id);
-    let log_opts = LogOptions::new(&recover_path);
-    let mut sled_opts = Config::default();
-    sled_opts = sled_opts.path(&recover_path);
-    let persistent_config = PersistentStorageConfig::with(recover_path.clone(), log_opts, sled_opts);
-
-    let recover = Path::new(&recover_path).exists();
-
-    let op_config = OmniPaxosConfig {
-        pid: node.id,
-        configuration_id: node_id.try_into().unwrap(),
-        peers: node.peers.to_vec(),
-        ..Default::default()
+    let config = match NodeConfig::load(Node::from_args()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load node configuration: {}", e);
+            std::process::exit(1);
+        }
     };
-
-    // let storage = MemoryStorage::<KeyValue, ()>::default();
-    // let op = op_config.build(storage);
-    let mut op: OmniPaxos<KeyValue, () , PersistentStorage<KeyValue, ()>>;
-    if !recover
-    {
-        let persistent_storage = PersistentStorage::<KeyValue, ()>::new(persistent_config);
-        op = op_config.build(persistent_storage);
-        println!("New instance of Omni-paxos created with recovery path: {}", recover_path);
-    }
-    else
-    {
-        let recovered_storage: PersistentStorage<KeyValue, ()> = PersistentStorage::open(persistent_config);
-        op = op_config.build(recovered_storage);
-        op.fail_recovery();
-        println!("Recovered old instance of Omni-paxos with recovery path: {}", recover_path);
+    logger::init(config.log_level);
+    let node_id = config.id;
+    let addrs = config.bind.clone();
+    // a restarted node resumes the latest configuration it took part in, a joining node waits until
+    // the members of the configuration it is added to tell it about it
+    let membership = match Membership::load(&config.data_dir) {
+        Some(membership) => Some(membership),
+        None if config.join => None,
+        None => {
+            let mut nodes = vec![node_id];
+            nodes.extend(config.peers.iter().copied());
+            Some(Membership { config_id: membership::BASE_CONFIG_ID, nodes })
+        }
+    };
+    // a node with a persisted log of its configuration recovers from it
+    let recovering = match &membership {
+        Some(m) if m.nodes.contains(&node_id) => m.has_storage(&config.data_dir),
+        _ => false,
+    };
+    if config.recover && !recovering {
+        error!("Nothing to recover from, no persisted log in {}", config.data_dir.display());
+        std::process::exit(1);
     }
+    let op = match &membership {
+        Some(m) if m.nodes.contains(&node_id) => Some(m.build(node_id, &config.data_dir)),
+        _ => {
+            info!("Waiting to be added to the cluster");
+            None
+        }
+    };
 
-    let (sender1, receiver): (mpsc::Sender<(String, Vec<u8>)>, _) = mpsc::channel(32);
-    let (man_sender, man_receiver): (mpsc::Sender<(String, Vec<u8>)>, _) = mpsc::channel(32);
-    let (cmd_man_sender, cmd_man_receiver): (mpsc::Sender<(String, Vec<u8>)>, _) = mpsc::channel(32);
-    let (sender_man_sender, sender_man_receiver): (mpsc::Sender<(String, Vec<u8>)>, _) = mpsc::channel(32);
+    let (sender1, receiver): (mpsc::Sender<Event>, _) = mpsc::channel(32);
 
     let kv_store = Arc::new(Mutex::new(KVStore::new()));
+    let mut pending = PendingRequests::new(node_id);
+    let leader = Arc::new(LeaderInfo::new(node_id));
+    let watchers = Arc::new(Watchers::new());
+    let status = Arc::new(NodeStatus::new(recovering));
 
-    tokio::spawn(async move {
-        management::manager(man_receiver, cmd_man_receiver, sender_man_sender).await;
-    });
+    // the store is rebuilt before any client is served, the node reports itself as recovering until
+    // it has rejoined the cluster
+    let (decided, installed) = match &op {
+        Some(op) if recovering => rebuild_store(&node_id, op, &kv_store, &mut pending, &watchers).await,
+        _ => (0, false),
+    };
+    // a node that restarts before the state of the previous configuration was installed in its
+    // current one keeps holding back proposals until it is
+    let installing = op.is_some()
+        && !installed
+        && membership.as_ref().is_some_and(|m| m.config_id != membership::BASE_CONFIG_ID);
 
-    let new_kv_store = Arc::clone(&kv_store);
+    let man_client_addr = config.cluster.man_client();
+
+    let context = OpContext {
+        id: node_id,
+        data_dir: config.data_dir.clone(),
+        snapshot_interval: config.snapshot_interval,
+        kv_store: Arc::clone(&kv_store),
+        leader: Arc::clone(&leader),
+        watchers: Arc::clone(&watchers),
+        status: Arc::clone(&status),
+    };
+    let state = OpState { op, membership, idx: decided, installing };
+    let transport = PeerTransport::new(config.cluster.clone());
     tokio::spawn(async move {
-        op_command_handler(&node.id, op, receiver, new_kv_store, sender_man_receiver, man_sender).await;
+        op_command_handler(context, state, receiver, pending, transport).await;
     });
 
     let new_sender = sender1.clone();
+    let heartbeat_interval = config.heartbeat_interval;
     tokio::spawn(async move {
-        send_messages(new_sender).await;
+        timer::periodic(new_sender, Tick::Heartbeat, heartbeat_interval).await;
     });
 
     let new_kv_store = Arc::clone(&kv_store);
+    let new_leader = Arc::clone(&leader);
+    let new_watchers = Arc::clone(&watchers);
+    let new_status = Arc::clone(&status);
     let new_sender = sender1.clone();
+    let http_addr = addrs.http.clone();
+    let cluster = config.cluster.clone();
     tokio::spawn(async move {
-        http::api_server(new_kv_store, new_sender, &node.id).await;
+        http::api_server(new_kv_store, new_leader, new_watchers, new_status, cluster, new_sender, http_addr).await;
     });
 
-    let new_sender = sender1.clone();
+    let context = ClientContext {
+        kv_store: Arc::clone(&kv_store),
+        sender: sender1.clone(),
+        leader: Arc::clone(&leader),
+        watchers: Arc::clone(&watchers),
+        status: Arc::clone(&status),
+    };
+    let client_addr = addrs.client.clone();
     tokio::spawn(async move {
-        cmd_listener(new_sender, node.id).await;
+        cmd_listener(context, client_addr).await;
     });
 
     let new_sender = sender1.clone();
+    let (election_timeout, election_jitter) = (config.election_timeout, config.election_jitter);
     tokio::spawn(async move {
-        election_timeout(new_sender).await;
+        timer::jittered(new_sender, Tick::Election, election_timeout, election_jitter).await;
     });
 
 
 
 
-    let mut listen_addr: String = "127.0.0.1:".to_owned();
-    let listen_port: u64 = util::SERV_PORT_BASE + node.id;
-    listen_addr.push_str(&listen_port.to_string().to_owned());
-
-    println!("Starting Server listener on addr: {}", listen_addr);
-
-    let listener = TcpListener::bind(listen_addr).await.unwrap();
+    let listen_addr = addrs.peer.clone();
+    info!("Starting Server listener on addr: {}", listen_addr);
 
-    let mut man_listen_addr: String = "127.0.0.1:".to_owned();
-    let man_listen_port: u64 = util::MAN_PORT_BASE + node.id;
-    man_listen_addr.push_str(&man_listen_port.to_string().to_owned());
+    let listener = match TcpListener::bind(&listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind peer listener on {}: {}", listen_addr, e);
+            std::process::exit(1);
+        }
+    };
 
-    println!("Starting Manager listener on addr: {}", man_listen_addr);
+    let man_listen_addr = addrs.admin.clone();
+    info!("Starting Manager listener on addr: {}", man_listen_addr);
 
-    let man_listener = TcpListener::bind(man_listen_addr).await.unwrap();
+    let man_listener = match TcpListener::bind(&man_listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind management listener on {}: {}", man_listen_addr, e);
+            std::process::exit(1);
+        }
+    };
 
-    println!("Server successfully started - server ID: {}, Peer ID's: {:?}", node.id, node.peers);
+    info!("Server successfully started - server ID: {}, Peer ID's: {:?}", node_id, config.peers);
 
     loop {
         let sender_n = sender1.clone();
@@ -162,17 +320,18 @@ async fn main() {
             }
         }
 
-        if socket.is_some() {
+        if let Some(socket) = socket {
             tokio::spawn(async move {
-                handle_commands(socket.unwrap(),sender_n).await;
+                handle_commands(socket, sender_n).await;
             });
         }
 
-        if man_socket.is_some() {
-            println!("Received new management connection");
-            let man_sender_c = cmd_man_sender.clone();
+        if let Some(man_socket) = man_socket {
+            info!("Received new management connection");
+            let man_sender_c = sender1.clone();
+            let man_client_addr = man_client_addr.clone();
             tokio::spawn(async move {
-                handle_man_commands(man_socket.unwrap(), man_sender_c).await;
+                management::handle_connection(man_socket, man_sender_c, man_client_addr).await;
             });
         }
 
@@ -180,253 +339,763 @@ async fn main() {
 }
 
 
-async fn handle_commands(mut read_socket: TcpStream, sender: mpsc::Sender<(String, Vec<u8>)>) {
-    let mut buffer = Vec::with_capacity(8192); // use a dynamically allocated buffer
+/// Read the messages of another node from a peer connection and hand them to the Omni-paxos task
+async fn handle_commands(mut read_socket: TcpStream, sender: mpsc::Sender<Event>) {
     loop {
-        let n = match read_socket.read_buf(&mut buffer).await {
-            Ok(n) if n == 0 => break, // connection closed by remote
-            Ok(n) => n,
+        let frame = match codec::read_frame(&mut read_socket).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break, // connection closed by remote
             Err(e) => {
-                eprintln!("failed to read from socket: {}", e);
+                error!("failed to read from socket: {}", e);
                 break;
             }
         };
-        // Resize the buffer to exactly fit the number of bytes read
-        buffer.resize(n, 0);
-        // Send the received bytes over the mpsc Sender to another thread with the tag "handle"
-        if let Err(e) = sender.send(("handle".into(), buffer.clone())).await {
-            eprintln!("failed to send message over channel: {}", e);
-            break;
-        }
-    }
-}
-
-async fn handle_man_commands(mut read_socket: TcpStream, man_sender: mpsc::Sender<(String, Vec<u8>)>) {
-    // println!("handle_man_read called");
-    let mut buffer = Vec::with_capacity(8192); // use a dynamically allocated buffer
-    loop {
-        let n = match read_socket.read_buf(&mut buffer).await {
-            Ok(n) if n == 0 => break, // connection closed by remote
-            Ok(n) => n,
+        let msg: PeerMessage = match error::decode(&frame) {
+            Ok(msg) => msg,
             Err(e) => {
-                eprintln!("failed to read from socket: {}", e);
-                break;
+                warn!("Dropping peer message: {}", e);
+                continue;
             }
         };
-        // println!("Manager received command of size: {}", n);
-        // Resize the buffer to exactly fit the number of bytes read
-        buffer.resize(n, 0);
-        // println!("sending command to manager: {:?}", buffer);
-        // Send the received bytes over the mpsc Sender to another thread with the tag "handle"
-        if let Err(e) = man_sender.send(("handle".into(), buffer.clone())).await {
-            eprintln!("Failed to send message to manager thread over channel: {}", e);
+        if sender.send(Event::PeerMessage(msg)).await.is_err() {
+            error!("{}", Error::ChannelClosed("Omni-paxos"));
             break;
         }
     }
 }
 
-async fn send_messages(sender: mpsc::Sender<(String, Vec<u8>)>) {
-    // periodically check outgoing messages and send all in list
-    loop {
-        thread::sleep(time::Duration::from_millis(1));
-        sender.send(("send_outgoing".into(), vec![])).await.unwrap();
-    }
+/// Settings of the Omni-paxos task and the state it shares with the tasks serving clients
+struct OpContext {
+    id: u64,
+    data_dir: PathBuf,
+    snapshot_interval: u64,
+    kv_store: Arc<Mutex<KVStore>>,
+    leader: Arc<LeaderInfo>,
+    watchers: Arc<Watchers>,
+    status: Arc<NodeStatus>,
 }
 
-async fn election_timeout(sender: mpsc::Sender<(String, Vec<u8>)>){
-    loop{
-        thread::sleep(time::Duration::from_millis(100));
-        sender.send(("election_timeout".into(), vec![])).await.unwrap();
-    }
+/// Omni-paxos instance the task starts with, if this node is a member of a configuration
+struct OpState {
+    op: Option<OmniPaxosKV>,
+    membership: Option<Membership>,
+    /// Decided index of the instance applied to the store
+    idx: u64,
+    installing: bool,
 }
 
 async fn op_command_handler(
-    id: &u64,
-    mut op: OmniPaxos<KeyValue, (), PersistentStorage<KeyValue, ()>>,
-    mut receiver: mpsc::Receiver<(String, Vec<u8>)>,
-    kv_store: Arc<Mutex<KVStore>>,
-    mut man_receiver: mpsc::Receiver<(String, Vec<u8>)>,
-    man_sender: mpsc::Sender<(String, Vec<u8>)>
+    context: OpContext,
+    state: OpState,
+    mut receiver: mpsc::Receiver<Event>,
+    mut pending: PendingRequests,
+    mut transport: PeerTransport,
 ) {
-    let mut idx: u64 = 0;
-    while let Some(action) = receiver.recv().await {
-        match (action.0.as_str(), action.1) {
-            ("handle", encrypted) => {
-                let msg: Message<KeyValue, ()> = bincode::deserialize(&encrypted).unwrap();
-                // println!("handling message, querying manager");
-                man_sender.send(("get_broken_links".into(), Vec::new())).await.unwrap();
-                let res = man_receiver.recv().await.unwrap();
-                // println!("received from manager: {:?}", res);
+    let OpContext { id, data_dir, snapshot_interval, kv_store, leader, watchers, status } = context;
+    let id = &id;
+    let OpState { mut op, mut membership, mut idx, mut installing } = state;
+    // `installing` is set between switching to a new configuration and deciding the state installed
+    // in it, client proposals are held back meanwhile so they are ordered after the installed state
+    let mut held: Vec<Proposal> = vec![];
+    // state of the previous configuration, kept by the installer until it is installed so it can
+    // propose it again if it gets lost
+    let mut install: Option<KVSnapshot> = None;
+    let mut last_install = Instant::now();
+    let mut install_retry = INSTALL_RETRY_INTERVAL;
+    let mut last_expiry = Instant::now();
+    // nodes the management client has cut this node off from
+    let mut broken_links: Vec<u64> = vec![];
+    while let Some(event) = receiver.recv().await {
+        match event {
+            Event::PeerMessage(msg) => {
+                let (config_id, msg) = match msg {
+                    PeerMessage::OmniPaxos { config_id, message } => (config_id, message),
+                    PeerMessage::Reconfigured(new) => {
+                        // configurations are only announced once their stop-sign is decided. A joining
+                        // node learns about the configuration it was added to this way, and so does a
+                        // member of the previous configuration that missed the stop-sign while it was
+                        // down or cut off. The installed state brings its store up to date.
+                        let current = membership.as_ref().map_or(0, |m| m.config_id);
+                        if new.config_id <= current {
+                            continue;
+                        }
+                        if new.nodes.contains(id) {
+                            info!("Joining configuration {} with nodes {:?}", new.config_id, new.nodes);
+                            op = Some(start_configuration(id, &new, &data_dir));
+                            membership = Some(new);
+                            idx = 0;
+                            installing = true;
+                            install = None;
+                        } else if op.is_some() {
+                            info!("Removed from the cluster in configuration {}", new.config_id);
+                            if let Err(e) = new.save(&data_dir) {
+                                error!("Failed to persist configuration: {}", e);
+                            }
+                            op = None;
+                            membership = Some(new);
+                        }
+                        continue;
+                    }
+                };
                 let sender = msg.get_sender();
-                if (res.1.clone()).contains(&(sender as u8)) {
-                    println!("link to receiver {} is broken, ignoring handling message", sender);
+                if broken_links.contains(&sender) {
+                    debug!("link to receiver {} is broken, ignoring handling message", sender);
                     continue;
                 }
+                match &membership {
+                    Some(m) if m.config_id == config_id => {}
+                    // the sender still runs a configuration whose stop-sign it missed
+                    Some(m) if m.config_id > config_id => {
+                        debug!("Node {} is behind in configuration {}, announcing {}", sender, config_id, m.config_id);
+                        announce_configuration(m, &[sender], &mut transport);
+                        continue;
+                    }
+                    _ => {
+                        debug!("Ignoring message of configuration {}", config_id);
+                        continue;
+                    }
+                }
                 // println!("Handling incoming message: {:?}", msg);
-                op.handle_incoming(msg);
+                if let Some(op) = op.as_mut() {
+                    op.handle_incoming(msg);
+                }
             }
-            ("send_outgoing", ..) => {
-                for message in op.outgoing_messages() {
-                    let out_receiver = message.get_receiver();
-                    // NOTE: This is only for debug purposes - sometimes, we want to "break" connections
-                    // manually, so we filter messages based on their receiver ID
-                    // println!("sending outgoing messages, querying manager thread");
-                    man_sender.send(("get_broken_links".into(), Vec::new())).await.unwrap();
-                    let res = man_receiver.recv().await.unwrap();
-                    // // println!("received from manager: {:?}", res);
-                    if (res.1.clone()).contains(&(out_receiver as u8)) {
-                        println!("link to receiver {} is broken, ignoring sending message", out_receiver);
+            Event::ClientRequest { client, session, command, reply } => {
+                let op = match op.as_mut() {
+                    Some(op) => op,
+                    None => {
+                        let _ = reply.send(Response::Error("node is not a member of the cluster".into()));
                         continue;
                     }
-                    let port = util::SERV_PORT_BASE + out_receiver;
-                    let rec_addr = format!("127.0.0.1:{}", port);
-                    // println!("Connecting to receiver: {}", rec_addr);
-                    match TcpStream::connect(rec_addr).await {
-                        Ok(stream) => {
-                            let (_reader, mut writer) = io::split(stream);
-                            let msg_enc: Vec<u8> = bincode::serialize(&message).unwrap();
-                            writer.write_all(&msg_enc).await.unwrap();
-                        }
-                        Err(err) => {
-                            eprintln!("Error connecting to TCP stream: {}", err);
-                        }
-                    }
+                };
+                let request_id = pending.register(reply);
+                // the id of a session names the client across connections, its address doesn't
+                let client = match &session {
+                    Some(session) => format!("client:{}", session.client),
+                    None => client,
+                };
+                let proposal = Proposal { id: request_id, client, timestamp: pending::now_millis(), session, command };
+                debug!("proposal written to Omni-paxos: {:?}", proposal);
+                if installing {
+                    held.push(proposal);
+                } else if let Err(e) = op.append(proposal) {
+                    error!("Failed to append proposal: {:?}", e);
+                    pending.complete(&request_id, Response::Error("failed to append to Omni-paxos".into()));
                 }
             }
-            ("read", encrypted) => {
-                // get key for reading
-                let key: String = bincode::deserialize(&encrypted).unwrap();
-                println!("Read received: {}", key);
-
-                // get all decided values from index
-                let decided: Option<Vec<LogEntry<KeyValue, ()>>> = op.read_decided_suffix(0);
-                match decided {
-                    Some(vec) => {
-                        write_response_to_client(vec.to_vec(), key).await;
+            Event::Admin(AdminCommand::BreakLink(node)) => broken_links.push(node),
+            Event::Admin(AdminCommand::RestoreLinks) => broken_links.clear(),
+            Event::Admin(AdminCommand::GetLinks(reply)) => {
+                let _ = reply.send(broken_links.clone());
+            }
+            Event::Admin(command @ (AdminCommand::AddNode(..) | AdminCommand::RemoveNode(..))) => {
+                let (add, node) = match command {
+                    AdminCommand::AddNode(node) => (true, node),
+                    AdminCommand::RemoveNode(node) => (false, node),
+                    _ => continue,
+                };
+                if add && node == 0 {
+                    warn!("Can't add node 0, node ids start at 1");
+                    continue;
+                }
+                info!("Reconfiguring cluster: {:?}", command);
+                let (op, current) = match (op.as_mut(), &membership) {
+                    (Some(op), Some(m)) => (op, m),
+                    _ => {
+                        warn!("Can't reconfigure, node is not a member of the cluster");
+                        continue;
                     }
-                    _ => eprintln!("Unexpected error received when reading decided suffix"),
+                };
+                // a configuration with the same nodes would only force every node to switch for nothing
+                if add && current.nodes.contains(&node) {
+                    warn!("Can't add node {}, it is already a member", node);
+                    continue;
+                }
+                if !add && !current.nodes.contains(&node) {
+                    warn!("Can't remove node {}, it is not a member", node);
+                    continue;
+                }
+                if !add && current.nodes.len() == 1 {
+                    warn!("Can't remove node {}, it is the last member", node);
+                    continue;
+                }
+                // the state is moved to the new configuration as one log entry, which has to fit in a frame
+                let len = bincode::serialized_size(&KVSnapshot::from_store(&*kv_store.lock().await));
+                match len {
+                    Ok(len) if len <= MAX_INSTALL_LEN => {}
+                    Ok(len) => {
+                        warn!("Can't reconfigure, the state takes {} bytes, at most {} fit", len, MAX_INSTALL_LEN);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Can't reconfigure, failed to encode the state: {}", e);
+                        continue;
+                    }
+                }
+                let mut nodes = current.nodes.clone();
+                if add {
+                    nodes.push(node);
+                } else {
+                    nodes.retain(|n| *n != node);
+                }
+                info!("Proposing new configuration with nodes {:?}", nodes);
+                if let Err(e) = op.reconfigure(ReconfigurationRequest::with(nodes, None)) {
+                    error!("Failed to propose reconfiguration: {:?}", e);
                 }
             }
-            ("write", encrypted) => {
-                let kv: KeyValue = bincode::deserialize(&encrypted).unwrap();
-                let c = kv.clone();
-                op.append(kv).expect("Failed to append");
-                let k = c.key;
-                let v = c.value;
-                println!("key/value written to Omni-paxos: {} = {}", k, v);
+            Event::Tick(Tick::Election) => {
+                if let Some(op) = op.as_mut() {
+                    op.election_timeout();
+                }
             }
-            ("election_timeout", ..) => {
-                op.election_timeout()
+            Event::Tick(Tick::Heartbeat) => {
+                pending.prune();
+                if let Some(op) = op.as_mut() {
+                    // the leader proposes the removal of expired keys, so replicas remove them at the same point
+                    if leader.get() == Some(*id) && !installing && last_expiry.elapsed() >= EXPIRY_INTERVAL {
+                        last_expiry = Instant::now();
+                        let expired = kv_store.lock().await.expired(pending::now_millis());
+                        if !expired.is_empty() {
+                            debug!("Proposing expiry of {} keys", expired.len());
+                            let proposal = Proposal {
+                                id: pending.next_id(),
+                                client: format!("node {}", id),
+                                timestamp: pending::now_millis(),
+                                session: None,
+                                command: Command::Expire(expired),
+                            };
+                            if let Err(e) = op.append(proposal) {
+                                warn!("Failed to propose expiry: {:?}", e);
+                            }
+                        }
+                    }
+                }
+                // keep telling the members about the new configuration until its state is installed,
+                // joining nodes may not have been reachable the first time
+                if let (true, Some(m)) = (installing, &membership) {
+                    announce_configuration(m, &m.peers(*id), &mut transport);
+                }
+                // the proposal of the installed state may have been lost to a leader change
+                if let (true, Some(state), Some(op)) = (installing, &install, op.as_mut()) {
+                    if last_install.elapsed() >= install_retry {
+                        last_install = Instant::now();
+                        install_retry = (install_retry * 2).min(MAX_INSTALL_RETRY_INTERVAL);
+                        info!("State of the previous configuration not installed yet, proposing it again");
+                        propose_install(id, op, state.clone(), &mut pending);
+                    }
+                }
             }
-            other => {
-                println!("Unexpected command received: {:?}", other);
+        }
+
+        let current = match op.as_mut() {
+            Some(current) => current,
+            None => {
+                leader.set(None);
+                continue;
             }
+        };
+        leader.set(current.get_current_leader());
+        if current.get_current_leader().is_some() && status.set_ready() {
+            info!("Rejoined the cluster, ready at decided index {}", current.get_decided_idx());
         }
 
         // update kv_store
-        let new_idx = op.get_decided_idx();
+        let new_idx = current.get_decided_idx();
         if new_idx > idx {
-            println!("new idx: {}", new_idx);
+            debug!("new idx: {}", new_idx);
             // TODO: might be a more performant implementation
-            let decided = op.read_decided_suffix(idx);
-            match decided {
-                Some(suffix) => insert_suffix(id, suffix, &kv_store).await,
-                None => {}
-            }
+            let decided = current.read_decided_suffix(idx);
+            let installed = match decided {
+                Some(suffix) => insert_suffix(id, suffix, &kv_store, &mut pending, &watchers).await,
+                None => false,
+            };
             idx = new_idx;
+
+            if installed && installing {
+                info!("State of the previous configuration installed, releasing {} held proposals", held.len());
+                installing = false;
+                install = None;
+                for proposal in held.drain(..) {
+                    let request_id = proposal.id;
+                    if let Err(e) = current.append(proposal) {
+                        error!("Failed to append proposal: {:?}", e);
+                        pending.complete(&request_id, Response::Error("failed to append to Omni-paxos".into()));
+                    }
+                }
+            }
+
+            // compact the decided prefix of the log once enough entries have piled up
+            if snapshot_interval > 0 && new_idx - current.get_compacted_idx() >= snapshot_interval {
+                match current.snapshot(Some(new_idx), true) {
+                    Ok(..) => {
+                        info!("Snapshotted log up to index {}", new_idx);
+                        // keep at least one snapshot interval of older versions around for reads at an index
+                        let mut store = kv_store.lock().await;
+                        let below = store.applied().saturating_sub(snapshot_interval);
+                        store.prune(below);
+                    }
+                    Err(e) => warn!("Failed to snapshot log: {:?}", e),
+                }
+            }
+        }
+
+        // send what Omni-paxos produced while handling this event right away instead of polling for it
+        if let Some(m) = &membership {
+            flush_outgoing(current, m.config_id, &broken_links, &mut transport);
+        }
+
+        // switch to the next configuration once its stop-sign is decided
+        if let (Some(ss), Some(old)) = (current.is_reconfigured(), membership.clone()) {
+            if ss.config_id <= old.config_id {
+                continue;
+            }
+            let new = Membership { config_id: ss.config_id, nodes: ss.nodes };
+            announce_configuration(&new, &new.peers(*id), &mut transport);
+            if !new.nodes.contains(id) {
+                info!("Removed from the cluster in configuration {}", new.config_id);
+                if let Err(e) = new.save(&data_dir) {
+                    error!("Failed to persist configuration: {}", e);
+                }
+                op = None;
+                membership = Some(new);
+                continue;
+            }
+
+            info!("Switching to configuration {} with nodes {:?}", new.config_id, new.nodes);
+            let mut next = start_configuration(id, &new, &data_dir);
+            idx = 0;
+            installing = true;
+            install = None;
+            if new.installer(&old) == Some(*id) {
+                let state = KVSnapshot::from_store(&*kv_store.lock().await);
+                propose_install(id, &mut next, state.clone(), &mut pending);
+                flush_outgoing(&mut next, new.config_id, &broken_links, &mut transport);
+                install = Some(state);
+                last_install = Instant::now();
+                install_retry = INSTALL_RETRY_INTERVAL;
+            }
+            op = Some(next);
+            membership = Some(new);
         }
     }
 }
 
-/// Insert decided suffix into the kv_store
-async fn insert_suffix(_: &u64, suffix: Vec<LogEntry<KeyValue, ()>>, kv_store: &Arc<Mutex<KVStore>>) {
-    println!("insert_suffix");
+/// Persist a configuration this node is a member of and start its Omni-paxos instance
+fn start_configuration(id: &u64, membership: &Membership, data_dir: &Path) -> OmniPaxosKV {
+    if let Err(e) = membership.save(data_dir) {
+        error!("Failed to persist configuration: {}", e);
+    }
+    membership.build(*id, data_dir)
+}
+
+/// Send the messages Omni-paxos has queued for the other nodes
+fn flush_outgoing(op: &mut OmniPaxosKV, config_id: u32, broken_links: &[u64], transport: &mut PeerTransport) {
+    for message in op.outgoing_messages() {
+        let out_receiver = message.get_receiver();
+        // NOTE: This is only for debug purposes - sometimes, we want to "break" connections
+        // manually, so we filter messages based on their receiver ID
+        if broken_links.contains(&out_receiver) {
+            debug!("link to receiver {} is broken, ignoring sending message", out_receiver);
+            continue;
+        }
+        match error::encode(&PeerMessage::OmniPaxos { config_id, message }) {
+            Ok(msg_enc) => transport.send(out_receiver, msg_enc),
+            Err(e) => error!("Dropping message to peer {}: {}", out_receiver, e),
+        }
+    }
+}
+
+/// Propose the state of the previous configuration as an entry of the new one
+fn propose_install(id: &u64, op: &mut OmniPaxosKV, state: KVSnapshot, pending: &mut PendingRequests) {
+    let proposal = Proposal {
+        id: pending.next_id(),
+        client: format!("node {}", id),
+        timestamp: pending::now_millis(),
+        session: None,
+        command: Command::Install(state),
+    };
+    if let Err(e) = op.append(proposal) {
+        error!("Failed to propose state of the previous configuration: {:?}", e);
+    }
+}
+
+/// Tell nodes that a configuration has been decided
+fn announce_configuration(membership: &Membership, nodes: &[u64], transport: &mut PeerTransport) {
+    let msg_enc = match error::encode(&PeerMessage::Reconfigured(membership.clone())) {
+        Ok(msg_enc) => msg_enc,
+        Err(e) => {
+            error!("Failed to announce configuration {}: {}", membership.config_id, e);
+            return;
+        }
+    };
+    for node in nodes {
+        transport.send(*node, msg_enc.clone());
+    }
+}
+
+/// Rebuild the store of a restarted node from its persisted log: the snapshot of the compacted prefix
+/// and the decided entries after it. Returns the decided index replayed up to and whether the state
+/// of a previous configuration was installed.
+async fn rebuild_store(
+    id: &u64,
+    op: &OmniPaxosKV,
+    kv_store: &Arc<Mutex<KVStore>>,
+    pending: &mut PendingRequests,
+    watchers: &Watchers,
+) -> (u64, bool) {
+    let decided = op.get_decided_idx();
+    info!("Recovering store from persisted log up to decided index {}", decided);
+    let installed = match op.read_decided_suffix(0) {
+        Some(suffix) => insert_suffix(id, suffix, kv_store, pending, watchers).await,
+        None => false,
+    };
+    info!("Store recovered at index {}", kv_store.lock().await.applied());
+    (decided, installed)
+}
+
+/// Insert decided suffix into the kv_store and answer the requests this node proposed. Returns whether
+/// the state of a previous configuration was installed, which a snapshot of the log includes.
+async fn insert_suffix(
+    id: &u64,
+    suffix: Vec<LogEntry<Proposal, KVSnapshot>>,
+    kv_store: &Arc<Mutex<KVStore>>,
+    pending: &mut PendingRequests,
+    watchers: &Watchers,
+) -> bool {
+    debug!("insert_suffix");
+    let mut installed = false;
+    let mut store = kv_store.lock().await;
     for entry in suffix {
         match entry {
-            Decided(KeyValue { key, value } ) => {
-                println!("Inserted {} -> {}", key, value);
-                kv_store.lock().await.insert(key, value);
-            },
-            _ => {},
+            Decided(proposal) => {
+                let request_id = proposal.id;
+                installed |= matches!(proposal.command, Command::Install(..));
+                let response = store::apply(&mut store, proposal);
+                if request_id.node == *id {
+                    pending.complete(&request_id, response);
+                }
+            }
+            // the compacted prefix of the log, covering everything from its start
+            Snapshotted(snapshotted) => {
+                info!("Restoring store from snapshot up to index {}", snapshotted.trimmed_idx);
+                *store = snapshotted.snapshot.restore();
+                installed = true;
+            }
+            Trimmed(idx) => error!("Log trimmed up to index {} without a snapshot, store can't be rebuilt", idx),
+            _ => {}
         }
     }
+    // still holding the store, so watches started meanwhile don't see these changes twice
+    if let Some(index) = store.take_restored() {
+        watchers.restored(index);
+    }
+    watchers.publish(store.take_changes());
+    installed
+}
+
+/// State shared by the tasks serving client connections
+#[derive(Clone)]
+struct ClientContext {
+    kv_store: Arc<Mutex<KVStore>>,
+    sender: mpsc::Sender<Event>,
+    leader: Arc<LeaderInfo>,
+    watchers: Arc<Watchers>,
+    status: Arc<NodeStatus>,
 }
 
-async fn cmd_listener(sender: mpsc::Sender<(String, Vec<u8>)>, id: u64) {
-    let listen_addr = format!("127.0.0.1:{}", util::CMD_PORT_BASE + id);
-    println!("listening on addr: {}", listen_addr);
-    let listener = TcpListener::bind(listen_addr).await.unwrap();
+async fn cmd_listener(context: ClientContext, listen_addr: String) {
+    info!("listening on addr: {}", listen_addr);
+    let listener = match TcpListener::bind(&listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind client listener on {}: {}", listen_addr, e);
+            return;
+        }
+    };
     loop {
         if let Ok((socket, _)) = listener.accept().await {
-            let (mut reader, _) = io::split(socket);
-            let mut buffer = vec![0u8; 128];
-            loop {
-                match reader.read(&mut buffer).await {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        handle_command(&sender, &buffer[..n]).await;
-                    },
-                    Err(e) => {
-                        eprintln!("Error reading from socket: {}", e);
-                        break;
-                    }
-                }
-            }
+            let context = context.clone();
+            tokio::spawn(async move {
+                handle_client(socket, context).await;
+            });
         } else {
-            eprintln!("Failed to accept incoming connection");
+            error!("Failed to accept incoming connection");
         }
     }
 }
 
-async fn handle_command(sender: &mpsc::Sender<(String, Vec<u8>)>, buffer: &[u8]) {
-    let message: String = bincode::deserialize(buffer).unwrap();
-    let msg_vec: Vec<&str> = message.split_whitespace().collect();
+/// Serve one client connection, replies are written back on the same connection
+async fn handle_client(socket: TcpStream, context: ClientContext) {
+    // recorded with the entries proposed for this connection
+    let client = match socket.peer_addr() {
+        Ok(addr) => format!("tcp:{}", addr),
+        Err(..) => "tcp:unknown".into(),
+    };
+    let (mut reader, mut writer) = io::split(socket);
 
-    match msg_vec.get(0) {
-        Some(&"read") => {
-            // println!("handling read command");
-            if let Some(key) = msg_vec.get(1) {
-                sender.send(("read".into(), bincode::serialize(&key.to_string()).unwrap())).await.unwrap();
+    // replies may complete out of order, a single task owns the write half
+    let (reply_sender, mut reply_receiver) = mpsc::channel::<ClientResponse>(32);
+    tokio::spawn(async move {
+        while let Some(reply) = reply_receiver.recv().await {
+            if let Err(e) = codec::write_message(&mut writer, &reply).await {
+                error!("Error writing response to client: {}", e);
+                break;
             }
         }
-        Some(&"write") => {
-            // println!("handling write command");
-            let key = msg_vec.get(1).cloned().unwrap_or_default();
-            let value = msg_vec.get(2).map(|s| s.trim()).unwrap_or_default().to_string();
-            let kv = KeyValue { key: key.to_string(), value };
-            sender.send(("write".into(), bincode::serialize(&kv).unwrap())).await.unwrap();
-        }
-        Some(&"delete") => {
-            let key = msg_vec.get(1).cloned().unwrap_or_default();
-            let value= "";
-            let kv = KeyValue { key: key.to_string(), value: value.to_string() };
-            sender.send(("write".into(), bincode::serialize(&kv).unwrap())).await.unwrap();
-        }
-        Some(cmd) => println!("Unknown command received: {:?}", cmd),
-        None => {}
+    });
+
+    loop {
+        // a frame that doesn't decode is answered and skipped, a broken framing ends the connection
+        let error = match codec::read_frame(&mut reader).await {
+            Ok(None) => break,
+            Ok(Some(frame)) => match error::decode::<ClientRequest>(&frame) {
+                Ok(request) => {
+                    handle_command(request, &client, &context, &reply_sender).await;
+                    continue;
+                }
+                Err(e) => e,
+            },
+            Err(e) => Error::Io(e),
+        };
+        warn!("Bad request from {}: {}", client, error);
+        let response = Response::Error(error.to_string());
+        let _ = reply_sender.send(ClientResponse { id: util::UNKNOWN_REQUEST_ID, response }).await;
+        if let Error::Io(..) = error {
+            break;
+        }
     }
 }
 
+/// What a client request turns into
+enum Action {
+    /// Propose the command and reply once it has been decided
+    Propose(Command),
+    /// Reply right away without going through the log
+    Reply(Response),
+}
 
-async fn write_response_to_client(log: Vec<LogEntry<KeyValue, ()>>, key: String) {
-    let stream = TcpStream::connect(format!("127.0.0.1:{}", util::CMD_PORT_BASE)).await.unwrap();
-    let (_, mut writer): (_, WriteHalf<_>) = split(stream);
+async fn handle_command(
+    request: ClientRequest,
+    client: &str,
+    context: &ClientContext,
+    reply_sender: &mpsc::Sender<ClientResponse>,
+) {
+    let mut args: Vec<&[u8]> = request.args.iter().map(|arg| arg.as_slice()).collect();
 
-    // Search for the latest entry with the given key
-    for entry in log.iter().rev() {
-        match entry {
-            Decided(kv) if kv.key == key => {
-                let response = format!("{}", kv.value);
-                let message: Vec<u8> = bincode::serialize(&response).unwrap();
-                writer.write_all(&message).await.unwrap();
+    // `at=<INDEX>` as the last argument serves reads and scans from the store as of a past log index
+    let at = match args.last() {
+        Some(arg) if args.len() > 2 && arg.starts_with(b"at=") && is_read(args[0]) => {
+            match parse::<u64>(&arg[b"at=".len()..]) {
+                Some(at) => {
+                    args.pop();
+                    Some(at)
+                }
+                None => {
+                    let response = Response::Error("at=<INDEX> expects a log index".into());
+                    let _ = reply_sender.send(ClientResponse { id: request.id, response }).await;
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let action = match args.first().copied() {
+        Some(b"read") => {
+            // println!("handling read command");
+            match (args.get(1), args.get(2).copied()) {
+                // linearizable reads go through the log so they observe every write decided before them
+                (Some(key), Some(b"linearizable")) if at.is_none() => Action::Propose(Command::Read(key.to_vec())),
+                (Some(key), None) | (Some(key), Some(b"local")) => {
+                    Action::Reply(context.kv_store.lock().await.read(key, at))
+                }
+                _ => Action::Reply(Response::Error("Usage: read <KEY> [local|linearizable|at=<INDEX>]".into())),
+            }
+        }
+        Some(b"scan") => {
+            match (args.get(1), args.get(2), scan_limit(args.get(3))) {
+                (Some(start), Some(end), Some(limit)) => {
+                    let token = args.get(4).copied();
+                    Action::Reply(store::scan(&*context.kv_store.lock().await, start, end, limit, token, at))
+                }
+                _ => Action::Reply(Response::Error("Usage: scan <START> <END> [LIMIT] [TOKEN] [at=<INDEX>]".into())),
+            }
+        }
+        Some(b"prefix") => {
+            match (args.get(1), scan_limit(args.get(2))) {
+                (Some(prefix), Some(limit)) => {
+                    let token = args.get(3).copied();
+                    Action::Reply(store::prefix(&*context.kv_store.lock().await, prefix, limit, token, at))
+                }
+                _ => Action::Reply(Response::Error("Usage: prefix <PREFIX> [LIMIT] [TOKEN] [at=<INDEX>]".into())),
+            }
+        }
+        Some(b"history") => {
+            match (args.get(1), args.get(2).map(|limit| parse::<usize>(limit))) {
+                (Some(key), None) => {
+                    Action::Reply(context.kv_store.lock().await.history(key, store::DEFAULT_HISTORY_LIMIT))
+                }
+                (Some(key), Some(Some(limit))) => Action::Reply(context.kv_store.lock().await.history(key, limit)),
+                _ => Action::Reply(Response::Error("Usage: history <KEY> [LIMIT]".into())),
+            }
+        }
+        Some(b"write") => {
+            // println!("handling write command");
+            // a time-to-live too large to be represented is rejected like one that doesn't parse
+            let expires = args.get(3).map(|ttl| ttl.strip_prefix(b"ttl=").and_then(parse::<u64>).and_then(pending::expiry));
+            match (args.get(1), args.get(2), expires) {
+                (Some(key), Some(value), None) => {
+                    Action::Propose(Command::Put(KeyValue { key: key.to_vec(), value: value.to_vec(), expires: None }))
+                }
+                (Some(key), Some(value), Some(Some(expires))) => {
+                    let put = KeyValue { key: key.to_vec(), value: value.to_vec(), expires: Some(expires) };
+                    Action::Propose(Command::Put(put))
+                }
+                _ => Action::Reply(Response::Error("Usage: write <KEY> <VALUE> [ttl=<SECS>]".into())),
+            }
+        }
+        Some(b"cas") => {
+            match (args.get(1), args.get(2), args.get(3)) {
+                (Some(key), Some(expected), Some(new)) => {
+                    Action::Propose(Command::Cas { key: key.to_vec(), expected: expected.to_vec(), new: new.to_vec() })
+                }
+                _ => Action::Reply(Response::Error("Usage: cas <KEY> <EXPECTED> <NEW>".into())),
+            }
+        }
+        Some(b"delete") => {
+            match args.get(1) {
+                Some(key) => Action::Propose(Command::Delete(key.to_vec())),
+                None => Action::Reply(Response::Error("Usage: delete <KEY>".into())),
+            }
+        }
+        Some(op @ (b"watch" | b"watch_prefix")) => {
+            let from = match args.get(2).map(|from| parse::<u64>(from)) {
+                None => Ok(None),
+                Some(Some(from)) => Ok(Some(from)),
+                Some(None) => Err(()),
+            };
+            match (args.get(1), from) {
+                (Some(key), Ok(from)) => {
+                    let watched = match op {
+                        b"watch" => Watched::Key(key.to_vec()),
+                        _ => Watched::Prefix(key.to_vec()),
+                    };
+                    watch(request.id, watched, from, context, reply_sender).await;
+                    return;
+                }
+                _ => Action::Reply(Response::Error("Usage: watch|watch_prefix <KEY> [FROM_INDEX]".into())),
+            }
+        }
+        Some(b"txn") => match parse_transaction(&args[1..]) {
+            Ok(command) => Action::Propose(command),
+            Err(e) => Action::Reply(Response::Error(e)),
+        },
+        Some(b"leader") => Action::Reply(Response::Leader(context.leader.get())),
+        Some(b"status") => {
+            let applied = context.kv_store.lock().await.applied();
+            Action::Reply(Response::Status { status: context.status.get(), applied, leader: context.leader.get() })
+        }
+        Some(cmd) => Action::Reply(Response::Error(format!("Unknown command: {}", String::from_utf8_lossy(cmd)))),
+        None => Action::Reply(Response::Error("Empty command".into())),
+    };
+
+    match action {
+        Action::Propose(command) => {
+            // requests going through the log are sent to the leader, if it is known and not this node
+            if let Some(leader) = context.leader.redirect() {
+                let response = Response::Redirect { leader };
+                let _ = reply_sender.send(ClientResponse { id: request.id, response }).await;
                 return;
             }
-            _ => continue,
+            // reply once the command has been decided, without blocking the connection
+            let context = context.clone();
+            let reply_sender = reply_sender.clone();
+            let client = client.to_string();
+            tokio::spawn(async move {
+                let response =
+                    pending::propose(&context.sender, client, request.session, command).await;
+                let _ = reply_sender.send(ClientResponse { id: request.id, response }).await;
+            });
+        }
+        // answered locally
+        Action::Reply(response) => {
+            let _ = reply_sender.send(ClientResponse { id: request.id, response }).await;
         }
     }
+}
 
-    // Key not found, send empty response to command window server
-    let empty_response: String = String::new();
-    let message: Vec<u8> = bincode::serialize(&empty_response).unwrap();
-    writer.write_all(&message).await.unwrap();
-}
\ No newline at end of file
+/// Limit argument of a scan, the default if it is missing and `None` if it is not a number
+fn scan_limit(arg: Option<&&[u8]>) -> Option<usize> {
+    match arg {
+        Some(limit) => parse(limit),
+        None => Some(store::DEFAULT_SCAN_LIMIT),
+    }
+}
+
+/// Operations that accept `at=<INDEX>`
+fn is_read(op: &[u8]) -> bool {
+    matches!(op, b"read" | b"scan" | b"prefix")
+}
+
+/// Numeric argument of a request, `None` if it is not valid UTF-8 or does not parse
+fn parse<T: FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Stream the changes of a watch back on the connection, each tagged with the id of the request
+/// that started it
+async fn watch(
+    id: u64,
+    watched: Watched,
+    from: Option<u64>,
+    context: &ClientContext,
+    reply_sender: &mpsc::Sender<ClientResponse>,
+) {
+    let mut events = match context.watchers.watch(&context.kv_store, watched, from).await {
+        Ok(events) => events,
+        Err(e) => {
+            let _ = reply_sender.send(ClientResponse { id, response: Response::Error(e) }).await;
+            return;
+        }
+    };
+    let reply_sender = reply_sender.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let response = match event {
+                Ok(event) => Response::Watch(event),
+                Err(e) => Response::Error(e),
+            };
+            // stops once the connection is closed
+            if reply_sender.send(ClientResponse { id, response }).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Parse the clauses of a `txn` command: `equals <KEY> <VALUE>` and `version <KEY> <N>` conditions
+/// followed by `put <KEY> <VALUE>` and `delete <KEY>` writes
+fn parse_transaction(args: &[&[u8]]) -> Result<Command, String> {
+    const USAGE: &str = "Usage: txn [equals <KEY> <VALUE>|version <KEY> <N>]... (put <KEY> <VALUE>|delete <KEY>)...";
+    let mut conditions = vec![];
+    let mut writes = vec![];
+    let mut args = args.iter();
+    while let Some(clause) = args.next() {
+        let mut arg = || args.next().map(|arg| arg.to_vec()).ok_or_else(|| USAGE.to_string());
+        match *clause {
+            b"equals" => conditions.push(Condition::Equals { key: arg()?, value: arg()? }),
+            b"version" => {
+                let key = arg()?;
+                let version = parse(&arg()?).ok_or_else(|| USAGE.to_string())?;
+                conditions.push(Condition::Version { key, version });
+            }
+            b"put" => writes.push(Write::Put(KeyValue { key: arg()?, value: arg()?, expires: None })),
+            b"delete" => writes.push(Write::Delete(arg()?)),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    if writes.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    Ok(Command::Transaction { conditions, writes })
+}
@@ -1,120 +1,109 @@
-use tokio::io::{AsyncWriteExt, split, WriteHalf};
+//! Commands of the management client, used to simulate broken links and to change the configuration
+//! of the cluster
+
+use log::{debug, error, info};
+use tokio::io::{split, WriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
-#[path="./util.rs"]
-mod util;
+use crate::codec;
+use crate::error::{self, Error};
+use crate::Event;
 
-struct ManState {
-    broken_links: Vec<u8>
+/// Command of the management client, carried out by the Omni-paxos task
+#[derive(Debug)]
+pub enum AdminCommand {
+    /// Drop every message to and from a node, to simulate partial connectivity
+    BreakLink(u64),
+    RestoreLinks,
+    /// Reply with the nodes whose links are broken
+    GetLinks(oneshot::Sender<Vec<u64>>),
+    AddNode(u64),
+    RemoveNode(u64),
 }
 
-pub async fn manager(
-    mut receiver: mpsc::Receiver<(String, Vec<u8>)>,
-    mut cmd_receiver: mpsc::Receiver<(String, Vec<u8>)>,
-    sender: mpsc::Sender<(String, Vec<u8>)>
-) {
-    let mut state = ManState { broken_links: vec![] };
+/// Serve one connection of the management client, answering on the address it listens on
+pub async fn handle_connection(mut socket: TcpStream, events: mpsc::Sender<Event>, man_client_addr: String) {
     loop {
-        let mut cmd_rec = None;
-        let mut rec = None;
-        tokio::select! {
-            f = receiver.recv() => rec = f,
-            b = cmd_receiver.recv() => cmd_rec = b
+        let frame = match codec::read_frame(&mut socket).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break, // connection closed by remote
+            Err(e) => {
+                error!("failed to read from socket: {}", e);
+                break;
+            }
+        };
+        let result = match error::decode::<String>(&frame) {
+            Ok(command) => {
+                debug!("deserialized management message: {:?}", command);
+                execute(&command, &events).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(Some(links)) => reply(&man_client_addr, Ok(links)).await,
+            Ok(None) => {}
+            Err(e) => {
+                error!("Bad management command: {}", e);
+                reply(&man_client_addr, Err(e)).await;
+            }
         }
+    }
+}
 
-        // println!("manager received values: {:?}, {:?}", rec, cmd_rec);
-
-        if cmd_rec.is_some() {
-            // handle received command value
-            state = handle_cmd_message(cmd_rec, state).await;
+/// Hand a command to the Omni-paxos task, returns the broken links if they were asked for
+async fn execute(command: &str, events: &mpsc::Sender<Event>) -> Result<Option<Vec<u64>>, Error> {
+    let (name, id) = parse_command(command)?;
+    let mut links = None;
+    let command = match name {
+        "break_link" => {
+            info!("Breaking link: {}", id);
+            AdminCommand::BreakLink(id)
         }
-
-        if rec.is_some() {
-            state = handle_rec_message(rec, sender.clone(), state).await;
+        "restore_links" => {
+            info!("Restoring links");
+            AdminCommand::RestoreLinks
         }
+        "get_links" => {
+            info!("Returning broken links");
+            let (sender, receiver) = oneshot::channel();
+            links = Some(receiver);
+            AdminCommand::GetLinks(sender)
+        }
+        "add_node" => AdminCommand::AddNode(id),
+        "remove_node" => AdminCommand::RemoveNode(id),
+        other => return Err(Error::InvalidCommand(format!("unknown command {}", other))),
+    };
+    events.send(Event::Admin(command)).await.map_err(|_| Error::ChannelClosed("Omni-paxos"))?;
+    match links {
+        Some(links) => links.await.map(Some).map_err(|_| Error::ChannelClosed("Omni-paxos")),
+        None => Ok(None),
     }
 }
 
-async fn handle_cmd_message(cmd_rec: Option<(String, Vec<u8>)>, state: ManState) -> ManState {
-    let mut updated_state = ManState { broken_links: state.broken_links };
-    match cmd_rec {
-        Some(crecval) => {
-            match (crecval.0.as_str(), crecval.1) {
-                ("handle", msg_enc) => {
-                    let dec: Result<&str, _> = bincode::deserialize(&msg_enc);
-                    println!("deserialized management message: {:?}", dec);
-                    match dec {
-                        Ok(c) => {
-                            let mut s = c.split(" ");
-                            let command = s.next().unwrap();
-                            let id = s.next().unwrap().parse::<u8>().unwrap();
-                            match (command, id) {
-                                ("break_link", id) => {
-                                    println!("Breaking link: {}", id);
-                                    // break links to specified ID
-                                    updated_state.broken_links.push(id);
-                                }
-                                ("restore_links", ..) => {
-                                    println!("Restoring links");
-                                    // restore all links to original state
-                                    updated_state.broken_links = vec![];
-                                }
-                                ("get_links", ..) => {
-                                    println!("Returning broken links");
-                                    write_response_to_client(updated_state.broken_links.clone()).await;
-
-                                }
-                                _ => {
-                                    eprintln!("Unrecognized input from cmd-client received in manager process")
-                                }
-                            }
-                        }
-                        Err(..) => {}
-                    }
-                }
-                _ => {
-                    eprintln!("Unrecognized command received");
-                }
-            }
-        }
-        None => {} // should literally never happen
-    }
-    return updated_state;
+/// Split a management command into its name and node id, e.g. `break_link 2`
+fn parse_command(command: &str) -> Result<(&str, u64), Error> {
+    let mut s = command.split_whitespace();
+    let name = s.next().ok_or_else(|| Error::InvalidCommand("empty command".into()))?;
+    let id = s
+        .next()
+        .and_then(|id| id.parse::<u64>().ok())
+        .ok_or_else(|| Error::InvalidCommand(format!("{} expects a node id", name)))?;
+    Ok((name, id))
 }
 
-async fn handle_rec_message(rec: Option<(String, Vec<u8>)>,
-    sender: mpsc::Sender<(String, Vec<u8>)>,
-    state: ManState
-) -> ManState {
-    let updated_state = ManState { broken_links: state.broken_links };
-    match rec {
-        Some(recval) => {
-            match (recval.0.as_str(), recval.1) {
-                ("get_broken_links", ..) => {
-                    // return broken links
-                    let cloned = updated_state.broken_links.clone();
-                    let res = sender.send(("broken_links".into(), cloned)).await;
-                    match res {
-                        Ok(..) => {}
-                        Err(..) => eprintln!("failed to send links to sender"),
-                    }
-                }
-                _ => {
-                    eprintln!("Unrecognized input from op-process received in manager process")
-                }
-            }
-        }
-        None => {} // should literally never happen
+/// Answer the management client, errors are logged since there is no one else to tell
+async fn reply(man_client_addr: &str, res: Result<Vec<u64>, Error>) {
+    if let Err(e) = write_response_to_client(man_client_addr, res.map_err(|e| e.to_string())).await {
+        error!("Failed to reply to management client on {}: {}", man_client_addr, e);
     }
-    return updated_state;
 }
 
-async fn write_response_to_client(res: Vec<u8>) {
+async fn write_response_to_client(man_client_addr: &str, res: Result<Vec<u64>, String>) -> Result<(), Error> {
     // Connect to command window server
-    let stream = TcpStream::connect(format!("127.0.0.1:{}", util::MAN_CLIENT_PORT)).await.unwrap();
+    let stream = TcpStream::connect(man_client_addr).await?;
     let (_reader, mut writer): (_, WriteHalf<_>) = split(stream);
-    println!("sending msg to man client: {:?}", &res);
-    let ser = bincode::serialize(&res).unwrap();
-    writer.write_all(&ser).await.unwrap();
-}
\ No newline at end of file
+    debug!("sending msg to man client: {:?}", &res);
+    codec::write_message(&mut writer, &res).await?;
+    Ok(())
+}
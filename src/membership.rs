@@ -0,0 +1,97 @@
+//! Configuration of the cluster this node takes part in, persisted next to the data directory so a
+//! restarted node rejoins the latest configuration
+
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use commitlog::LogOptions;
+use log::info;
+use omnipaxos_core::omni_paxos::OmniPaxosConfig;
+use omnipaxos_storage::persistent_storage::{PersistentStorage, PersistentStorageConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::store::KVSnapshot;
+use crate::{OmniPaxosKV, Proposal};
+
+/// Configuration id of the cluster a node starts in when it isn't joining one
+pub const BASE_CONFIG_ID: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Membership {
+    pub config_id: u32,
+    pub nodes: Vec<u64>,
+}
+
+impl Membership {
+    pub fn load(data_dir: &Path) -> Option<Membership> {
+        let content = std::fs::read_to_string(membership_path(data_dir)).ok()?;
+        toml::from_str(&content).ok()
+    }
+
+    pub fn save(&self, data_dir: &Path) -> std::io::Result<()> {
+        let content = toml::to_string(self).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        std::fs::write(membership_path(data_dir), content)
+    }
+
+    pub fn peers(&self, id: u64) -> Vec<u64> {
+        self.nodes.iter().copied().filter(|n| *n != id).collect()
+    }
+
+    /// Directory of the Omni-paxos storage of this configuration, every configuration starts a new log
+    pub fn storage_path(&self, data_dir: &Path) -> PathBuf {
+        if self.config_id == BASE_CONFIG_ID {
+            data_dir.to_path_buf()
+        } else {
+            PathBuf::from(format!("{}_config{}", data_dir.display(), self.config_id))
+        }
+    }
+
+    /// Whether this node has persisted the log of this configuration, i.e. it is restarting
+    pub fn has_storage(&self, data_dir: &Path) -> bool {
+        self.storage_path(data_dir).exists()
+    }
+
+    /// Open the Omni-paxos instance of this configuration, recovering it if it has storage on disk
+    pub fn build(&self, id: u64, data_dir: &Path) -> OmniPaxosKV {
+        let recover_path = self.storage_path(data_dir).to_string_lossy().to_string();
+        let log_opts = LogOptions::new(&recover_path);
+        let mut sled_opts = sled::Config::default();
+        sled_opts = sled_opts.path(&recover_path);
+        let persistent_config = PersistentStorageConfig::with(recover_path.clone(), log_opts, sled_opts);
+
+        let recover = self.has_storage(data_dir);
+
+        let op_config = OmniPaxosConfig {
+            pid: id,
+            configuration_id: self.config_id,
+            peers: self.peers(id),
+            ..Default::default()
+        };
+
+        let mut op: OmniPaxosKV;
+        if !recover
+        {
+            let persistent_storage = PersistentStorage::<Proposal, KVSnapshot>::new(persistent_config);
+            op = op_config.build(persistent_storage);
+            info!("New instance of Omni-paxos created with recovery path: {}", recover_path);
+        }
+        else
+        {
+            let recovered_storage: PersistentStorage<Proposal, KVSnapshot> = PersistentStorage::open(persistent_config);
+            op = op_config.build(recovered_storage);
+            op.fail_recovery();
+            info!("Recovered old instance of Omni-paxos with recovery path: {}", recover_path);
+        }
+        op
+    }
+
+    /// The node that proposes the state of the old configuration as the first entry of this one:
+    /// the lowest id that is a member of both
+    pub fn installer(&self, old: &Membership) -> Option<u64> {
+        self.nodes.iter().copied().filter(|n| old.nodes.contains(n)).min()
+    }
+}
+
+fn membership_path(data_dir: &Path) -> PathBuf {
+    PathBuf::from(format!("{}_membership.toml", data_dir.display()))
+}
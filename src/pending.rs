@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+
+use crate::{Command, Event, RequestId};
+use crate::error::Error;
+use crate::util::{Response, Session};
+
+/// How long a client waits for its proposal to be decided before giving up
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Client requests proposed by this node that are parked until their entry is decided, owned by the
+/// Omni-paxos task
+pub struct PendingRequests {
+    node: u64,
+    next_seq: u64,
+    waiting: HashMap<RequestId, oneshot::Sender<Response>>,
+}
+
+impl PendingRequests {
+    pub fn new(node: u64) -> Self {
+        // start from the current time so ids of a restarted node don't collide with decided entries
+        // from its previous run that are replayed on recovery
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        PendingRequests { node, next_seq: start, waiting: HashMap::new() }
+    }
+
+    pub fn next_id(&mut self) -> RequestId {
+        self.next_seq += 1;
+        RequestId { node: self.node, seq: self.next_seq }
+    }
+
+    /// Allocate a request id and park the reply channel of the request under it
+    pub fn register(&mut self, reply: oneshot::Sender<Response>) -> RequestId {
+        let id = self.next_id();
+        self.waiting.insert(id, reply);
+        id
+    }
+
+    /// Deliver the response of a request, if anyone is still waiting for it
+    pub fn complete(&mut self, id: &RequestId, response: Response) {
+        if let Some(reply) = self.waiting.remove(id) {
+            let _ = reply.send(response);
+        }
+    }
+
+    /// Forget the requests whose client stopped waiting, e.g. after a timeout. Their entries may
+    /// still be decided, they just aren't answered.
+    pub fn prune(&mut self) {
+        self.waiting.retain(|_, reply| !reply.is_closed());
+    }
+}
+
+/// Milliseconds since the Unix epoch, as recorded in proposals
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// When a key written now with a time-to-live of `ttl` seconds expires, `None` if the time-to-live
+/// is too large to be represented
+pub fn expiry(ttl: u64) -> Option<u64> {
+    ttl.checked_mul(1000).and_then(|ms| now_millis().checked_add(ms))
+}
+
+/// Propose a command to Omni-paxos and wait until it has been decided
+pub async fn propose(
+    events: &mpsc::Sender<Event>,
+    client: String,
+    session: Option<Session>,
+    command: Command,
+) -> Response {
+    let (reply, receiver) = oneshot::channel();
+    if events.send(Event::ClientRequest { client, session, command, reply }).await.is_err() {
+        return Response::Error(Error::ChannelClosed("Omni-paxos").to_string());
+    }
+
+    match timeout(REQUEST_TIMEOUT, receiver).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(..)) => Response::Error("request dropped before it was decided".into()),
+        // the entry may have been lost to a leader change
+        Err(..) => Response::Timeout,
+    }
+}
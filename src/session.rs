@@ -0,0 +1,129 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, Proposal};
+use crate::util::{Response, Session};
+
+/// How long a session is remembered after its last request, in milliseconds. A request retried
+/// later than this is applied again.
+const SESSION_TIMEOUT: u64 = 60 * 60 * 1000;
+/// Number of results kept per session, retries of requests older than these are rejected
+const MAX_SESSION_RESULTS: usize = 64;
+/// How often idle sessions are discarded, in milliseconds of the time of the log
+const PRUNE_INTERVAL: u64 = 60 * 1000;
+
+/// Results of the requests of one client session
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct SessionResults {
+    /// Results of requests up to this sequence number have been discarded
+    floor: u64,
+    results: BTreeMap<u64, Response>,
+    /// When the latest request of the session was proposed, in milliseconds since the Unix epoch
+    last_seen: u64,
+}
+
+impl SessionResults {
+    fn expired(&self, now: u64) -> bool {
+        now > self.last_seen + SESSION_TIMEOUT
+    }
+}
+
+/// Deduplication table of the state machine. Every replica records the result of each request
+/// carrying a session when it is applied, so a request that is decided again after a client
+/// retried it gets the original result instead of being applied twice.
+///
+/// Whether a session has timed out is judged by the timestamp of the proposal being applied, so
+/// replicas agree on it no matter when they discard the session.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Sessions {
+    sessions: HashMap<u64, SessionResults>,
+    /// Timestamp of the latest proposal applied, the time sessions are discarded at
+    latest: u64,
+    /// Time of the log at which idle sessions are next discarded
+    next_prune: u64,
+}
+
+impl Sessions {
+    /// Result of an earlier request with the same session and sequence number, if there is one
+    pub fn duplicate(&self, session: &Session, timestamp: u64) -> Option<Response> {
+        let results = self.sessions.get(&session.client).filter(|results| !results.expired(timestamp))?;
+        match results.results.get(&session.seq) {
+            Some(response) => Some(response.clone()),
+            None if session.seq <= results.floor => Some(Response::Error(format!(
+                "request {} of session {} is too old to be deduplicated",
+                session.seq, session.client
+            ))),
+            None => None,
+        }
+    }
+
+    /// Remember the result of an applied request
+    pub fn record(&mut self, session: &Session, timestamp: u64, response: &Response) {
+        let results = self.sessions.entry(session.client).or_default();
+        if results.expired(timestamp) {
+            *results = SessionResults::default();
+        }
+        results.last_seen = results.last_seen.max(timestamp);
+        results.results.insert(session.seq, response.clone());
+        while results.results.len() > MAX_SESSION_RESULTS {
+            if let Some((seq, _)) = results.results.pop_first() {
+                results.floor = seq;
+            }
+        }
+    }
+
+    /// Advance the time of the log to the timestamp of a proposal being applied, discarding idle
+    /// sessions every `PRUNE_INTERVAL` of it. Driven by the log alone, so the table stays bounded
+    /// even if the log is never compacted.
+    pub fn observe(&mut self, timestamp: u64) {
+        self.latest = self.latest.max(timestamp);
+        if self.latest >= self.next_prune {
+            self.prune();
+            self.next_prune = self.latest + PRUNE_INTERVAL;
+        }
+    }
+
+    /// Time of the log, the latest timestamp observed
+    pub fn latest(&self) -> u64 {
+        self.latest
+    }
+
+    /// Discard sessions idle for well over the timeout as of the latest proposal applied, so every
+    /// replica discards the same sessions whatever its clock. Sessions are only discarded once no
+    /// proposal can still judge them alive, even one from a node whose clock is behind.
+    fn prune(&mut self) {
+        let now = self.latest;
+        self.sessions.retain(|_, results| !results.expired(now.saturating_sub(SESSION_TIMEOUT)));
+    }
+}
+
+/// Session of a proposal whose result is deduplicated. Reads are not, a retried read is simply
+/// served again.
+pub fn tracked(proposal: &Proposal) -> Option<&Session> {
+    match proposal.command {
+        Command::Read(..) => None,
+        _ => proposal.session.as_ref(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_sessions_discarded_as_log_time_advances() {
+        let mut sessions = Sessions::default();
+        sessions.observe(1000);
+        sessions.record(&Session { client: 1, seq: 1 }, 1000, &Response::Committed);
+        sessions.observe(1000 + SESSION_TIMEOUT);
+        sessions.record(&Session { client: 2, seq: 1 }, 1000 + SESSION_TIMEOUT, &Response::Committed);
+
+        // both are still alive for a proposal from a node whose clock is behind
+        sessions.observe(1000 + 2 * SESSION_TIMEOUT);
+        assert_eq!(sessions.sessions.len(), 2);
+        sessions.observe(1001 + 2 * SESSION_TIMEOUT + PRUNE_INTERVAL);
+        assert!(!sessions.sessions.contains_key(&1));
+        assert!(sessions.sessions.contains_key(&2));
+    }
+}
@@ -0,0 +1,27 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::util::Status;
+
+/// Whether this node is still rejoining the cluster after restarting from its persisted state,
+/// shared with the tasks serving clients
+pub struct NodeStatus {
+    recovering: AtomicBool,
+}
+
+impl NodeStatus {
+    pub fn new(recovering: bool) -> Self {
+        NodeStatus { recovering: AtomicBool::new(recovering) }
+    }
+
+    /// Mark the node ready, returns whether it was still recovering
+    pub fn set_ready(&self) -> bool {
+        self.recovering.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn get(&self) -> Status {
+        match self.recovering.load(Ordering::Relaxed) {
+            true => Status::Recovering,
+            false => Status::Ready,
+        }
+    }
+}
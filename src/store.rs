@@ -1,4 +1,867 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::ops::Bound;
 
-pub type KVStore = HashMap<String, String>;
+use log::debug;
+use omnipaxos_core::storage::Snapshot;
+use serde::{Deserialize, Serialize};
 
+use crate::{Command, Condition, KeyValue, Proposal, Write};
+use crate::pending::now_millis;
+use crate::session::{self, Sessions};
+use crate::util::{Change, Entry, Response, WatchEvent};
+use crate::watch::Watched;
+
+/// Value of a key as written by the entry at log index `version`, `None` for a delete
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Versioned {
+    pub version: u64,
+    pub value: Option<Vec<u8>>,
+    /// Client that issued the write and when it was proposed, copied from the log entry
+    pub client: String,
+    pub timestamp: u64,
+    /// When the value expires, in milliseconds since the Unix epoch
+    pub expires: Option<u64>,
+}
+
+impl Versioned {
+    /// The value, if it exists and has not expired at time `now`
+    pub fn live(&self, now: u64) -> Option<&Vec<u8>> {
+        self.value.as_ref().filter(|_| self.expires.is_none_or(|expires| expires > now))
+    }
+
+    fn change(&self) -> Change {
+        Change { index: self.version, value: self.value.clone(), client: self.client.clone(), timestamp: self.timestamp }
+    }
+}
+
+/// Changes to one key kept for history queries, oldest first
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct KeyHistory {
+    changes: VecDeque<Change>,
+    /// Whether older changes have been discarded to stay within `MAX_HISTORY_CHANGES`
+    truncated: bool,
+}
+
+impl KeyHistory {
+    fn push(&mut self, change: Change) {
+        self.changes.push_back(change);
+        while self.changes.len() > MAX_HISTORY_CHANGES {
+            self.changes.pop_front();
+            self.truncated = true;
+        }
+    }
+}
+
+/// Number of changes kept per key for history queries, older ones are discarded. The history is
+/// replicated with the snapshots, so every node has to keep the same number of changes.
+const MAX_HISTORY_CHANGES: usize = 100;
+
+/// Multi-version store ordered by key. Older versions of every key are kept until they are pruned,
+/// so reads can be served as of a past log index.
+///
+/// Log indexes are counted over every decided entry since the cluster started. They match the
+/// Omni-paxos decided index in the first configuration and continue from there in later ones,
+/// whose logs start over at 0.
+#[derive(Debug, Default)]
+pub struct KVStore {
+    /// Versions of every key, oldest first
+    keys: BTreeMap<Vec<u8>, Vec<Versioned>>,
+    /// Index of the last applied entry
+    applied: u64,
+    /// Oldest index reads can be served at, versions needed before it have been pruned
+    oldest: u64,
+    /// Expiry time and key of every latest version with a time-to-live
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+    /// Changes applied since they were last taken for the watches
+    changes: Vec<WatchEvent>,
+    /// Index the store was restored at from a snapshot since that was last taken for the watches,
+    /// the changes before it were never applied one by one
+    restored: Option<u64>,
+    /// Results of the client requests applied, to answer retries
+    sessions: Sessions,
+    /// Changes to every key, kept apart from the versions so pruning them doesn't shorten the history
+    history: HashMap<Vec<u8>, KeyHistory>,
+}
+
+/// Number of entries returned by a scan if the client does not ask for a limit
+pub const DEFAULT_SCAN_LIMIT: usize = 100;
+/// Upper bound for the number of entries returned by one scan
+pub const MAX_SCAN_LIMIT: usize = 1000;
+/// Number of changes returned by a history query if the client does not ask for a limit
+pub const DEFAULT_HISTORY_LIMIT: usize = 20;
+/// Upper bound for the number of keys removed by one expiry entry
+const MAX_EXPIRE_BATCH: usize = 100;
+
+impl KVStore {
+    pub fn new() -> Self {
+        KVStore::default()
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// Latest value of a key, unless it has expired at time `now`
+    pub fn get(&self, key: &[u8], now: u64) -> Option<&Vec<u8>> {
+        self.version_at(key, self.applied).and_then(|versioned| versioned.live(now))
+    }
+
+    /// Read a key as of index `at`, or the latest value if it is not given. Expired keys are
+    /// hidden even if their expiry has not been decided yet.
+    pub fn read(&self, key: &[u8], at: Option<u64>) -> Response {
+        let index = match self.index(at) {
+            Ok(index) => index,
+            Err(e) => return Response::Error(e),
+        };
+        match self.version_at(key, index) {
+            Some(versioned) => {
+                let value = versioned.live(now_millis()).cloned();
+                Response::Value { value, version: versioned.version, index }
+            }
+            None => Response::Value { value: None, version: 0, index },
+        }
+    }
+
+    /// Changes to the watched keys still kept by the store from log index `from` on, in log order
+    pub fn changes_since(&self, watched: &Watched, from: u64) -> Result<Vec<WatchEvent>, String> {
+        if from < self.oldest {
+            return Err(format!("index {} has been compacted, oldest index to watch from is {}", from, self.oldest));
+        }
+        let range = match watched {
+            Watched::Key(key) => (Bound::Included(key.as_slice()), Bound::Included(key.as_slice())),
+            Watched::Prefix(prefix) => (Bound::Included(prefix.as_slice()), Bound::Unbounded),
+        };
+        let mut changes: Vec<WatchEvent> = self
+            .keys
+            .range::<[u8], _>(range)
+            .take_while(|(key, _)| watched.matches(key))
+            .flat_map(|(key, versions)| {
+                versions
+                    .iter()
+                    .filter(|versioned| versioned.version >= from)
+                    .map(move |versioned| WatchEvent { key: key.clone(), change: versioned.change() })
+            })
+            .collect();
+        changes.sort_by_key(|event| event.change.index);
+        Ok(changes)
+    }
+
+    /// Changes applied since the last call, to be published to the watches
+    pub fn take_changes(&mut self) -> Vec<WatchEvent> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Index the store was restored at from a snapshot since the last call, the watches have
+    /// missed the changes before it
+    pub fn take_restored(&mut self) -> Option<u64> {
+        self.restored.take()
+    }
+
+    /// Keys whose time-to-live has passed at time `now`, with the expiry time of their value
+    pub fn expired(&self, now: u64) -> Vec<(Vec<u8>, u64)> {
+        self.expiries
+            .iter()
+            .take_while(|(expires, _)| *expires <= now)
+            .take(MAX_EXPIRE_BATCH)
+            .map(|(expires, key)| (key.clone(), *expires))
+            .collect()
+    }
+
+    /// Changes to a key, newest first
+    pub fn history(&self, key: &[u8], limit: usize) -> Response {
+        let history = self.history.get(key);
+        let changes = history
+            .into_iter()
+            .flat_map(|history| history.changes.iter().rev())
+            .take(limit.clamp(1, MAX_SCAN_LIMIT))
+            .cloned()
+            .collect();
+        Response::History { key: key.to_vec(), changes, truncated: history.is_some_and(|history| history.truncated) }
+    }
+
+    /// Latest values of every existing key that has not expired, in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        let now = now_millis();
+        self.range_at((Bound::Unbounded, Bound::Unbounded), self.applied)
+            .filter_map(move |(key, versioned)| versioned.live(now).map(|value| (key, value)))
+    }
+
+    /// Discard the versions that are only needed to read before index `below`
+    pub fn prune(&mut self, below: u64) {
+        let below = below.min(self.applied);
+        if below <= self.oldest {
+            return;
+        }
+        self.keys.retain(|_, versions| {
+            if let Some(visible) = versions.iter().rposition(|versioned| versioned.version <= below) {
+                versions.drain(..visible);
+            }
+            // a key deleted before `below` and not written since is gone for good
+            !(versions.len() == 1 && versions[0].value.is_none() && versions[0].version <= below)
+        });
+        self.oldest = below;
+    }
+
+    /// Index a read at `at` is served at, if versions are still kept for it
+    fn index(&self, at: Option<u64>) -> Result<u64, String> {
+        match at {
+            None => Ok(self.applied),
+            Some(at) if at > self.applied => {
+                Err(format!("index {} has not been applied yet, last applied index is {}", at, self.applied))
+            }
+            Some(at) if at < self.oldest => {
+                Err(format!("index {} has been compacted, oldest readable index is {}", at, self.oldest))
+            }
+            Some(at) => Ok(at),
+        }
+    }
+
+    /// Whether a transaction condition holds for the latest versions, expiry is judged at time `now`
+    fn holds(&self, condition: &Condition, now: u64) -> bool {
+        let current = match condition {
+            Condition::Equals { key, .. } | Condition::Version { key, .. } => self.version_at(key, self.applied),
+        };
+        check(condition, current, now)
+    }
+
+    fn version_at(&self, key: &[u8], at: u64) -> Option<&Versioned> {
+        self.keys.get(key)?.iter().rev().find(|versioned| versioned.version <= at)
+    }
+
+    /// Versions visible at index `at` of the keys in `range`, keys not existing at `at` are skipped
+    fn range_at<'a>(
+        &'a self,
+        range: (Bound<&[u8]>, Bound<&[u8]>),
+        at: u64,
+    ) -> impl Iterator<Item = (&'a Vec<u8>, &'a Versioned)> + 'a {
+        self.keys.range::<[u8], _>(range).filter_map(move |(key, versions)| {
+            versions
+                .iter()
+                .rev()
+                .find(|versioned| versioned.version <= at)
+                .filter(|versioned| versioned.value.is_some())
+                .map(|versioned| (key, versioned))
+        })
+    }
+
+    fn write(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, expires: Option<u64>, client: &str, timestamp: u64) {
+        let versions = self.keys.entry(key.clone()).or_default();
+        if let Some(previous) = versions.last().and_then(|versioned| versioned.expires) {
+            self.expiries.remove(&(previous, key.clone()));
+        }
+        if let (Some(expires), Some(..)) = (expires, &value) {
+            self.expiries.insert((expires, key.clone()));
+        }
+        let versioned = Versioned { version: self.applied, value, client: client.to_string(), timestamp, expires };
+        self.history.entry(key.clone()).or_default().push(versioned.change());
+        self.changes.push(WatchEvent { key, change: versioned.change() });
+        versions.push(versioned);
+    }
+}
+
+/// Apply a decided entry to the store, returning the response for the client that issued it. A
+/// request decided again after its client retried it is not applied, it gets the original response.
+pub fn apply(store: &mut KVStore, proposal: Proposal) -> Response {
+    // an installed state carries the index it was taken at
+    if !matches!(proposal.command, Command::Install(..)) {
+        store.applied += 1;
+        store.sessions.observe(proposal.timestamp);
+    }
+    let session = session::tracked(&proposal).copied();
+    if let Some(session) = &session {
+        if let Some(response) = store.sessions.duplicate(session, proposal.timestamp) {
+            debug!("Request {} of session {} was already applied", session.seq, session.client);
+            return response;
+        }
+    }
+    let timestamp = proposal.timestamp;
+    let response = execute(store, proposal);
+    if let Some(session) = &session {
+        store.sessions.record(session, timestamp, &response);
+    }
+    response
+}
+
+fn execute(store: &mut KVStore, proposal: Proposal) -> Response {
+    let Proposal { client, timestamp, command, .. } = proposal;
+    match command {
+        Command::Put(KeyValue { key, value, expires }) => {
+            debug!("Inserted {} -> {}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value));
+            store.write(key, Some(value), expires, &client, timestamp);
+            Response::Committed
+        }
+        Command::Delete(key) => {
+            debug!("Deleted {}", String::from_utf8_lossy(&key));
+            store.write(key, None, None, &client, timestamp);
+            Response::Committed
+        }
+        Command::Cas { key, expected, new } => {
+            // expiry is judged by the time the CAS was proposed at, so every replica agrees on it
+            let current = store.get(&key, timestamp).cloned();
+            let success = current.as_ref() == Some(&expected);
+            if success {
+                store.write(key.clone(), Some(new), None, &client, timestamp);
+            }
+            debug!("CAS on {} {}", String::from_utf8_lossy(&key), if success { "succeeded" } else { "failed" });
+            Response::Cas { key, success, current }
+        }
+        Command::Read(key) => store.read(&key, None),
+        Command::Transaction { conditions, writes } => {
+            if let Some(failed) = conditions.iter().find(|condition| !store.holds(condition, timestamp)) {
+                debug!("Transaction aborted, {:?} does not hold", failed);
+                return Response::Transaction { committed: false, failed: Some(describe(failed)) };
+            }
+            for write in writes {
+                match write {
+                    Write::Put(KeyValue { key, value, expires }) => store.write(key, Some(value), expires, &client, timestamp),
+                    Write::Delete(key) => store.write(key, None, None, &client, timestamp),
+                }
+            }
+            Response::Transaction { committed: true, failed: None }
+        }
+        Command::Expire(keys) => {
+            for (key, expires) in keys {
+                // the key may have been written again since the expiry was proposed
+                let current = store.version_at(&key, store.applied);
+                if current.is_some_and(|versioned| versioned.value.is_some() && versioned.expires == Some(expires)) {
+                    debug!("Expired {}", String::from_utf8_lossy(&key));
+                    store.write(key, None, None, &client, timestamp);
+                }
+            }
+            Response::Committed
+        }
+        Command::Install(snapshot) => {
+            // the state is proposed again until it is installed, so it may be decided more than once
+            if snapshot.applied <= store.applied {
+                debug!("State of the previous configuration already installed");
+            } else {
+                debug!("Installing state of the previous configuration");
+                *store = snapshot.restore();
+            }
+            Response::Committed
+        }
+    }
+}
+
+/// Check a condition against the current version of its key. Keys that don't exist or have
+/// expired count as version 0.
+fn check(condition: &Condition, current: Option<&Versioned>, now: u64) -> bool {
+    let live = current.filter(|versioned| versioned.live(now).is_some());
+    match condition {
+        Condition::Equals { value, .. } => live.and_then(|versioned| versioned.value.as_ref()) == Some(value),
+        Condition::Version { version, .. } => live.map_or(0, |versioned| versioned.version) == *version,
+    }
+}
+
+fn describe(condition: &Condition) -> String {
+    match condition {
+        Condition::Equals { key, value } => {
+            format!("{} equals {:?}", String::from_utf8_lossy(key), String::from_utf8_lossy(value))
+        }
+        Condition::Version { key, version } => format!("{} at version {}", String::from_utf8_lossy(key), version),
+    }
+}
+
+/// Keys in `[start, end)` as of index `at`, resuming at `token` if it is given
+pub fn scan(
+    store: &KVStore,
+    start: &[u8],
+    end: &[u8],
+    limit: usize,
+    token: Option<&[u8]>,
+    at: Option<u64>,
+) -> Response {
+    let index = match store.index(at) {
+        Ok(index) => index,
+        Err(e) => return Response::Error(e),
+    };
+    let start = token.filter(|token| *token > start).unwrap_or(start);
+    if start >= end {
+        return Response::Scan { entries: vec![], next: None, index };
+    }
+    page(store.range_at((Bound::Included(start), Bound::Excluded(end)), index), limit, index)
+}
+
+/// Keys starting with `prefix` as of index `at`, resuming at `token` if it is given
+pub fn prefix(store: &KVStore, prefix: &[u8], limit: usize, token: Option<&[u8]>, at: Option<u64>) -> Response {
+    let index = match store.index(at) {
+        Ok(index) => index,
+        Err(e) => return Response::Error(e),
+    };
+    let start = token.filter(|token| *token > prefix).unwrap_or(prefix);
+    let entries = store
+        .range_at((Bound::Included(start), Bound::Unbounded), index)
+        .take_while(|(key, _)| key.starts_with(prefix));
+    page(entries, limit, index)
+}
+
+/// Take up to `limit` entries, the key following them is the token for the next page
+fn page<'a>(entries: impl Iterator<Item = (&'a Vec<u8>, &'a Versioned)>, limit: usize, index: u64) -> Response {
+    let limit = limit.clamp(1, MAX_SCAN_LIMIT);
+    let now = now_millis();
+    let mut entries = entries.filter_map(|(key, versioned)| versioned.live(now).map(|value| (key, value, versioned)));
+    let page = entries
+        .by_ref()
+        .take(limit)
+        .map(|(key, value, versioned)| Entry { key: key.clone(), value: value.clone(), version: versioned.version })
+        .collect();
+    Response::Scan { entries: page, next: entries.next().map(|(key, ..)| key.clone()), index }
+}
+
+/// Snapshot of the store used to compact the Omni-paxos log.
+///
+/// A snapshot created from a slice of the log does not know the state before that slice, so
+/// operations depending on it (a CAS on a key not written earlier in the slice) can't be evaluated
+/// yet. Such an operation and everything after it are kept in `ops` and evaluated once the
+/// snapshot is merged into, or restored on top of, the state preceding it.
+///
+/// An installed state may be decided more than once, whether it is a duplicate is only known with
+/// the state before it, so it is kept in `ops` too unless that state is known.
+///
+/// Only the latest version of every key is kept, reads before the snapshot are no longer possible
+/// once a node restores it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct KVSnapshot {
+    /// Whether the state before this snapshot was discarded by an installed state
+    reset: bool,
+    /// Number of entries covered, `ops` included except installed states. Counted from the start of
+    /// the slice unless `reset` is set, in which case it is the index of the installed state.
+    applied: u64,
+    /// Final version of every key written before `ops`, counted like `applied`
+    values: HashMap<Vec<u8>, Versioned>,
+    /// Operations that are evaluated in order after `values`
+    ops: Vec<Proposal>,
+    /// Results of the client requests evaluated before `ops`
+    sessions: Sessions,
+    /// Changes to every key written before `ops`, their indexes counted like `applied`
+    history: HashMap<Vec<u8>, KeyHistory>,
+}
+
+impl KVSnapshot {
+    pub fn from_store(store: &KVStore) -> Self {
+        let values = store
+            .range_at((Bound::Unbounded, Bound::Unbounded), store.applied)
+            .map(|(key, versioned)| (key.clone(), versioned.clone()))
+            .collect();
+        KVSnapshot {
+            reset: true,
+            applied: store.applied,
+            values,
+            ops: vec![],
+            sessions: store.sessions.clone(),
+            history: store.history.clone(),
+        }
+    }
+
+    /// Rebuild the store from a snapshot covering the log from its start
+    pub fn restore(&self) -> KVStore {
+        let mut store = KVStore::new();
+        store.applied = self.applied - entries(&self.ops);
+        store.oldest = store.applied;
+        store.sessions = self.sessions.clone();
+        store.history = self.history.clone();
+        for (key, versioned) in &self.values {
+            if versioned.value.is_some() {
+                if let Some(expires) = versioned.expires {
+                    store.expiries.insert((expires, key.clone()));
+                }
+                store.keys.insert(key.clone(), vec![versioned.clone()]);
+            }
+        }
+        for proposal in &self.ops {
+            apply(&mut store, proposal.clone());
+        }
+        // restored changes are not delivered to watches, they have to resume from a later index
+        store.changes.clear();
+        store.restored = Some(store.applied);
+        store
+    }
+
+    /// Add an entry to the snapshot, evaluating it right away if it only depends on known keys.
+    /// Whether a client request is a retry depends on the requests before it, so requests with a
+    /// session are only evaluated once the snapshot is complete.
+    fn push(&mut self, proposal: Proposal, complete: bool) {
+        if let Command::Install(snapshot) = &proposal.command {
+            if !self.ops.is_empty() || !(complete || self.reset) {
+                self.ops.push(proposal);
+            } else if !self.reset || snapshot.applied > self.applied {
+                *self = snapshot.clone();
+            }
+            return;
+        }
+        self.applied += 1;
+        self.sessions.observe(proposal.timestamp);
+        let session = session::tracked(&proposal).copied();
+        if !self.ops.is_empty() || (session.is_some() && !complete) {
+            self.ops.push(proposal);
+            return;
+        }
+        if let Some(session) = &session {
+            if self.sessions.duplicate(session, proposal.timestamp).is_some() {
+                return;
+            }
+        }
+        match self.evaluate(&proposal, complete) {
+            Some(response) => {
+                if let Some(session) = &session {
+                    self.sessions.record(session, proposal.timestamp, &response);
+                }
+            }
+            None => self.ops.push(proposal),
+        }
+    }
+
+    /// Apply an entry to `values`, returning the response its client got, or `None` if it depends
+    /// on keys from before an incomplete snapshot
+    fn evaluate(&mut self, proposal: &Proposal, complete: bool) -> Option<Response> {
+        let (key, value, expires) = match &proposal.command {
+            Command::Put(KeyValue { key, value, expires }) => (key, Some(value), *expires),
+            Command::Delete(key) => (key, None, None),
+            Command::Cas { key, expected, new } => {
+                let current = match self.values.get(key) {
+                    Some(current) => current.live(proposal.timestamp).cloned(),
+                    None if complete => None,
+                    None => return None,
+                };
+                if current.as_ref() != Some(expected) {
+                    return Some(Response::Cas { key: key.clone(), success: false, current });
+                }
+                self.write(key, Some(new.clone()), None, proposal);
+                return Some(Response::Cas { key: key.clone(), success: true, current });
+            }
+            Command::Expire(keys) => {
+                if !complete && keys.iter().any(|(key, _)| !self.values.contains_key(key)) {
+                    return None;
+                }
+                for (key, expires) in keys {
+                    let current = self.values.get(key);
+                    if current.is_some_and(|versioned| versioned.value.is_some() && versioned.expires == Some(*expires)) {
+                        self.write(key, None, None, proposal);
+                    }
+                }
+                return Some(Response::Committed);
+            }
+            Command::Transaction { conditions, writes } => {
+                for condition in conditions {
+                    let key = match condition {
+                        Condition::Equals { key, .. } | Condition::Version { key, .. } => key,
+                    };
+                    // versions in an incomplete snapshot are counted from its start, they can only be
+                    // compared once it has been merged
+                    let known = match condition {
+                        Condition::Equals { .. } => complete || self.values.contains_key(key),
+                        Condition::Version { .. } => complete,
+                    };
+                    if !known {
+                        return None;
+                    }
+                    if !check(condition, self.values.get(key), proposal.timestamp) {
+                        let failed = Some(describe(condition));
+                        return Some(Response::Transaction { committed: false, failed });
+                    }
+                }
+                for write in writes {
+                    match write {
+                        Write::Put(KeyValue { key, value, expires }) => {
+                            self.write(key, Some(value.clone()), *expires, proposal)
+                        }
+                        Write::Delete(key) => self.write(key, None, None, proposal),
+                    }
+                }
+                return Some(Response::Transaction { committed: true, failed: None });
+            }
+            // reads are not recorded in sessions, their response is never needed
+            Command::Read(..) | Command::Install(..) => return Some(Response::Committed),
+        };
+        self.write(key, value.cloned(), expires, proposal);
+        Some(Response::Committed)
+    }
+
+    fn write(&mut self, key: &[u8], value: Option<Vec<u8>>, expires: Option<u64>, proposal: &Proposal) {
+        let versioned = Versioned {
+            version: self.applied,
+            value,
+            client: proposal.client.clone(),
+            timestamp: proposal.timestamp,
+            expires,
+        };
+        self.history.entry(key.to_vec()).or_default().push(versioned.change());
+        self.values.insert(key.to_vec(), versioned);
+    }
+}
+
+/// Number of log entries among deferred operations, installed states don't count as one
+fn entries(ops: &[Proposal]) -> u64 {
+    ops.iter().filter(|proposal| !matches!(proposal.command, Command::Install(..))).count() as u64
+}
+
+impl Snapshot<Proposal> for KVSnapshot {
+    fn create(entries: &[Proposal]) -> Self {
+        let mut snapshot = KVSnapshot::default();
+        for proposal in entries {
+            snapshot.push(proposal.clone(), false);
+        }
+        snapshot
+    }
+
+    /// Omni-paxos only merges deltas into the snapshot covering the log from its start, so keys
+    /// missing from `self` don't exist and every pending operation can be evaluated. A delta never
+    /// discards the state before it, its installed states are kept in `ops`.
+    fn merge(&mut self, delta: Self) {
+        let pending = std::mem::take(&mut self.ops);
+        self.applied -= entries(&pending);
+        for proposal in pending {
+            self.push(proposal, true);
+        }
+        // versions in the delta are counted from its start, which directly follows `self`
+        let offset = self.applied;
+        for (key, mut versioned) in delta.values {
+            versioned.version += offset;
+            self.values.insert(key, versioned);
+        }
+        for (key, delta_history) in delta.history {
+            let history = self.history.entry(key).or_default();
+            history.truncated |= delta_history.truncated;
+            for mut change in delta_history.changes {
+                change.index += offset;
+                history.push(change);
+            }
+        }
+        self.applied += delta.applied - entries(&delta.ops);
+        self.sessions.observe(delta.sessions.latest());
+        for proposal in delta.ops {
+            self.push(proposal, true);
+        }
+    }
+
+    fn use_snapshots() -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RequestId;
+    use crate::util::Session;
+
+    fn proposal(seq: u64, command: Command) -> Proposal {
+        Proposal { id: RequestId { node: 1, seq }, client: "test".into(), timestamp: 1000 + seq, session: None, command }
+    }
+
+    fn put(key: &str, value: &str) -> Command {
+        Command::Put(KeyValue { key: key.into(), value: value.into(), expires: None })
+    }
+
+    fn cas(key: &str, expected: &str, new: &str) -> Command {
+        Command::Cas { key: key.into(), expected: expected.into(), new: new.into() }
+    }
+
+    /// Applied index, latest version of every key and the keys pending expiry
+    type Contents = (u64, Vec<(Vec<u8>, Option<Vec<u8>>, u64, Option<u64>)>, Vec<(Vec<u8>, u64)>);
+
+    fn contents(store: &KVStore) -> Contents {
+        let keys = store
+            .range_at((Bound::Unbounded, Bound::Unbounded), store.applied)
+            .map(|(key, versioned)| (key.clone(), versioned.value.clone(), versioned.version, versioned.expires))
+            .collect();
+        (store.applied, keys, store.expired(u64::MAX))
+    }
+
+    /// Index and value of every change to every key, in key order
+    type History = Vec<(Vec<u8>, Vec<(u64, Option<Vec<u8>>)>)>;
+
+    fn history(store: &KVStore) -> History {
+        let mut history: Vec<_> = store
+            .history
+            .iter()
+            .map(|(key, history)| (key.clone(), history.changes.iter().map(|c| (c.index, c.value.clone())).collect()))
+            .collect();
+        history.sort();
+        history
+    }
+
+    /// Restore the snapshot of `a` merged with the snapshot of `b`, and check it matches applying
+    /// both slices to a store directly. Returns both stores.
+    fn check_merge(a: &[Proposal], b: &[Proposal]) -> (KVStore, KVStore) {
+        let mut direct = KVStore::new();
+        for proposal in a.iter().chain(b) {
+            apply(&mut direct, proposal.clone());
+        }
+        let mut snapshot = KVSnapshot::create(a);
+        snapshot.merge(KVSnapshot::create(b));
+        let restored = snapshot.restore();
+        assert_eq!(contents(&restored), contents(&direct));
+        assert_eq!(history(&restored), history(&direct));
+
+        // a single slice covering both restores the same store
+        let whole: Vec<Proposal> = a.iter().chain(b).cloned().collect();
+        assert_eq!(contents(&KVSnapshot::create(&whole).restore()), contents(&direct));
+        (restored, direct)
+    }
+
+    #[test]
+    fn cas_on_key_from_before_slice() {
+        let a = [proposal(1, put("k", "v1")), proposal(2, put("other", "x"))];
+        let b = [
+            proposal(3, cas("k", "v1", "v2")),
+            proposal(4, cas("k", "v1", "v3")),
+            proposal(5, cas("missing", "x", "y")),
+            proposal(6, put("after", "z")),
+        ];
+        let (restored, _) = check_merge(&a, &b);
+        assert_eq!(restored.get(b"k", 0), Some(&b"v2".to_vec()));
+    }
+
+    #[test]
+    fn version_conditioned_transaction() {
+        let txn = |version, key: &str| Command::Transaction {
+            conditions: vec![Condition::Version { key: b"k".to_vec(), version }],
+            writes: vec![Write::Put(KeyValue { key: key.into(), value: b"w".to_vec(), expires: None })],
+        };
+        let a = [proposal(1, put("k", "v1")), proposal(2, put("k", "v2"))];
+        let b = [proposal(3, txn(2, "committed")), proposal(4, txn(1, "aborted")), proposal(5, put("k", "v3"))];
+        let (restored, _) = check_merge(&a, &b);
+        assert!(restored.get(b"committed", 0).is_some());
+        assert!(restored.get(b"aborted", 0).is_none());
+    }
+
+    #[test]
+    fn expire_entry() {
+        let ttl = |key: &str, expires| Command::Put(KeyValue { key: key.into(), value: b"v".to_vec(), expires: Some(expires) });
+        let a = [proposal(1, ttl("old", 5)), proposal(2, ttl("rewritten", 5))];
+        let b = [
+            proposal(3, put("rewritten", "again")),
+            proposal(4, ttl("new", 7)),
+            proposal(5, Command::Expire(vec![(b"old".to_vec(), 5), (b"rewritten".to_vec(), 5), (b"new".to_vec(), 7)])),
+        ];
+        let (restored, _) = check_merge(&a, &b);
+        assert_eq!(restored.get(b"rewritten", 0), Some(&b"again".to_vec()));
+        assert!(!restored.keys.contains_key(b"old".as_slice()));
+        assert!(restored.expired(u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn history_limit_discards_oldest_changes() {
+        let mut history = KeyHistory::default();
+        let last = MAX_HISTORY_CHANGES as u64 + 2;
+        for index in 1..=last {
+            let change = Change { index, value: None, client: "test".into(), timestamp: index };
+            history.push(change);
+        }
+        assert!(history.truncated);
+        assert_eq!(history.changes.len(), MAX_HISTORY_CHANGES);
+        assert_eq!(history.changes.front().map(|change| change.index), Some(3));
+        assert_eq!(history.changes.back().map(|change| change.index), Some(last));
+    }
+
+    #[test]
+    fn install_in_middle_of_slice() {
+        let mut previous = KVStore::new();
+        for seq in 1..=10 {
+            apply(&mut previous, proposal(seq, put(&format!("key{}", seq), "old")));
+        }
+        let state = KVSnapshot::from_store(&previous);
+
+        let a = [proposal(11, put("key1", "lost"))];
+        let b = [
+            proposal(12, put("before", "lost")),
+            proposal(13, Command::Install(state.clone())),
+            proposal(14, cas("key2", "old", "new")),
+            // a retried proposal of the same state is not installed again
+            proposal(15, Command::Install(state)),
+            proposal(16, put("after", "kept")),
+        ];
+        let (restored, _) = check_merge(&a, &b);
+        assert_eq!(restored.applied(), 12);
+        assert_eq!(restored.get(b"key1", 0), Some(&b"old".to_vec()));
+        assert_eq!(restored.get(b"key2", 0), Some(&b"new".to_vec()));
+        assert!(restored.get(b"before", 0).is_none());
+        assert_eq!(restored.get(b"after", 0), Some(&b"kept".to_vec()));
+    }
+
+    #[test]
+    fn session_tracked_retry() {
+        let sessioned = |seq, session_seq, command| Proposal {
+            session: Some(Session { client: 7, seq: session_seq }),
+            ..proposal(seq, command)
+        };
+        let a = [proposal(1, put("k", "v1")), sessioned(2, 1, cas("k", "v1", "v2"))];
+        // the retry of the CAS would fail if it were applied again
+        let b = [sessioned(3, 1, cas("k", "v1", "v2")), sessioned(4, 2, put("p", "x")), sessioned(5, 2, put("p", "x"))];
+        let (mut restored, mut direct) = check_merge(&a, &b);
+        assert_eq!(restored.keys[b"p".as_slice()].len(), 1);
+
+        // both answer a later retry with the original result
+        let retry = sessioned(6, 1, cas("k", "v1", "v2"));
+        for store in [&mut restored, &mut direct] {
+            let response = apply(store, retry.clone());
+            assert!(
+                matches!(&response, Response::Cas { success: true, current: Some(current), .. } if current == b"v1"),
+                "unexpected response {:?}",
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn sessions_pruned_by_log_time() {
+        let sessioned = Proposal { session: Some(Session { client: 7, seq: 1 }), ..proposal(2, cas("k", "v1", "v2")) };
+        let mut store = KVStore::default();
+        apply(&mut store, proposal(1, put("k", "v1")));
+        apply(&mut store, sessioned.clone());
+        // proposals are timestamped long before the local clock, which must not expire the session
+        store.prune(store.applied());
+        let retry = Proposal { id: RequestId { node: 1, seq: 3 }, ..sessioned };
+        let response = apply(&mut store, retry);
+        assert!(matches!(response, Response::Cas { success: true, .. }), "unexpected response {:?}", response);
+    }
+
+    /// Store with every key in `keys` written, in order
+    fn store_with(keys: &[&str]) -> KVStore {
+        let mut store = KVStore::new();
+        for (seq, key) in keys.iter().enumerate() {
+            apply(&mut store, proposal(seq as u64 + 1, put(key, "v")));
+        }
+        store
+    }
+
+    /// Keys of a page and the token for the next one
+    fn page_keys(response: Response) -> (Vec<String>, Option<String>) {
+        match response {
+            Response::Scan { entries, next, .. } => (
+                entries.into_iter().map(|entry| String::from_utf8(entry.key).unwrap()).collect(),
+                next.map(|next| String::from_utf8(next).unwrap()),
+            ),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scan_pages_through_range() {
+        let mut store = store_with(&["a", "b", "c", "d", "e", "f"]);
+        apply(&mut store, proposal(7, Command::Delete(b"b".to_vec())));
+
+        assert_eq!(page_keys(scan(&store, b"a", b"e", 2, None, None)), (vec!["a".into(), "c".into()], Some("d".into())));
+        // the last page ends exactly at `end`, which is excluded
+        assert_eq!(page_keys(scan(&store, b"a", b"e", 2, Some(b"d"), None)), (vec!["d".into()], None));
+        assert_eq!(page_keys(scan(&store, b"a", b"d", 2, Some(b"c"), None)), (vec!["c".into()], None));
+        // a token before the start doesn't widen the range, one at or past the end ends it
+        assert_eq!(page_keys(scan(&store, b"c", b"e", 1, Some(b"a"), None)), (vec!["c".into()], Some("d".into())));
+        assert_eq!(page_keys(scan(&store, b"a", b"e", 2, Some(b"e"), None)), (vec![], None));
+        assert_eq!(page_keys(scan(&store, b"d", b"c", 2, None, None)), (vec![], None));
+    }
+
+    #[test]
+    fn prefix_pages_through_keys() {
+        let store = store_with(&["p", "p1", "p2", "p3", "q1"]);
+
+        assert_eq!(page_keys(prefix(&store, b"p", 2, None, None)), (vec!["p".into(), "p1".into()], Some("p2".into())));
+        // the key after the last page doesn't start with the prefix
+        assert_eq!(page_keys(prefix(&store, b"p", 2, Some(b"p2"), None)), (vec!["p2".into(), "p3".into()], None));
+        assert_eq!(page_keys(prefix(&store, b"p", 2, Some(b"a"), None)), (vec!["p".into(), "p1".into()], Some("p2".into())));
+        assert_eq!(page_keys(prefix(&store, b"p", 2, Some(b"q"), None)), (vec![], None));
+        // pages as of an earlier index only see the keys written by then
+        assert_eq!(page_keys(prefix(&store, b"p", 5, None, Some(2))), (vec!["p".into(), "p1".into()], None));
+    }
+}
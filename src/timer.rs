@@ -0,0 +1,48 @@
+//! Timers driving the Omni-paxos task. They run on tokio time, so waiting for the next tick never
+//! blocks a worker thread.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use log::error;
+use tokio::sync::mpsc;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::error::Error;
+use crate::{Event, Tick};
+
+/// Send `tick` every `period`, ticks missed while the Omni-paxos task was busy are not made up for
+pub async fn periodic(sender: mpsc::Sender<Event>, tick: Tick, period: Duration) {
+    let mut interval = time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if sender.send(Event::Tick(tick)).await.is_err() {
+            error!("{}", Error::ChannelClosed("Omni-paxos"));
+            return;
+        }
+    }
+}
+
+/// Send `tick` every `period` plus a random delay of up to `jitter`, so nodes started together
+/// don't time out in lockstep and keep splitting the vote
+pub async fn jittered(sender: mpsc::Sender<Event>, tick: Tick, period: Duration, jitter: Duration) {
+    // hashers of a fresh `RandomState` are randomly keyed, which is all the randomness needed here
+    let random = RandomState::new();
+    let mut round: u64 = 0;
+    loop {
+        round += 1;
+        let mut hasher = random.build_hasher();
+        hasher.write_u64(round);
+        let extra = match jitter.as_millis() as u64 {
+            0 => 0,
+            max => hasher.finish() % (max + 1),
+        };
+        time::sleep(period + Duration::from_millis(extra)).await;
+        if sender.send(Event::Tick(tick)).await.is_err() {
+            error!("{}", Error::ChannelClosed("Omni-paxos"));
+            return;
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::cluster::Cluster;
+use crate::codec;
+
+/// Messages queued per peer before new ones are dropped, Omni-paxos resends what gets lost
+const PEER_QUEUE_SIZE: usize = 1024;
+
+/// Minimum time between two connection attempts to an unreachable peer
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// Long-lived outbound connections to the other nodes, one queue and writer task per peer so a slow
+/// or dead peer never blocks the Omni-paxos event loop
+pub struct PeerTransport {
+    cluster: Cluster,
+    queues: HashMap<u64, mpsc::Sender<Vec<u8>>>,
+}
+
+impl PeerTransport {
+    pub fn new(cluster: Cluster) -> Self {
+        PeerTransport { cluster, queues: HashMap::new() }
+    }
+
+    /// Queue an encoded message for a peer, connecting to it on first use
+    pub fn send(&mut self, peer: u64, message: Vec<u8>) {
+        let cluster = &self.cluster;
+        let queue = self.queues.entry(peer).or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel(PEER_QUEUE_SIZE);
+            tokio::spawn(peer_connection(peer, cluster.node(peer).peer, receiver));
+            sender
+        });
+        match queue.try_send(message) {
+            Ok(..) => {}
+            Err(TrySendError::Full(..)) => warn!("Outbound queue to peer {} is full, dropping message", peer),
+            Err(TrySendError::Closed(..)) => {
+                error!("Connection task for peer {} stopped", peer);
+                self.queues.remove(&peer);
+            }
+        }
+    }
+}
+
+/// Write queued messages to a peer over a single connection, reconnecting when it breaks
+async fn peer_connection(peer: u64, addr: String, mut queue: mpsc::Receiver<Vec<u8>>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut last_attempt: Option<Instant> = None;
+
+    while let Some(message) = queue.recv().await {
+        if stream.is_none() {
+            // messages for an unreachable peer are dropped until it is time to try again
+            if last_attempt.is_some_and(|t| t.elapsed() < RECONNECT_DELAY) {
+                continue;
+            }
+            last_attempt = Some(Instant::now());
+            match TcpStream::connect(&addr).await {
+                Ok(s) => {
+                    let _ = s.set_nodelay(true);
+                    info!("Connected to peer {} on addr: {}", peer, addr);
+                    stream = Some(s);
+                }
+                Err(e) => {
+                    warn!("Error connecting to peer {}: {}", peer, e);
+                    continue;
+                }
+            }
+        }
+
+        if let Some(s) = stream.as_mut() {
+            if let Err(e) = codec::write_frame(s, &message).await {
+                error!("Error sending message to peer {}: {}", peer, e);
+                stream = None;
+            }
+        }
+    }
+}
@@ -1,4 +1,102 @@
+use serde::{Deserialize, Serialize};
+
 pub const SERV_PORT_BASE: u64 = 50000;
 pub const MAN_PORT_BASE: u64 = 60000;
 pub const MAN_CLIENT_PORT: u64 = 62010;
-pub const CMD_PORT_BASE: u64 = 61000;
\ No newline at end of file
+pub const CMD_PORT_BASE: u64 = 61000;
+pub const HTTP_PORT_BASE: u64 = 9000;
+
+/// Id of the reply to a request that could not be decoded
+pub const UNKNOWN_REQUEST_ID: u64 = u64::MAX;
+
+/// Command sent from a client to a server node, `id` is chosen by the client to match the reply
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientRequest {
+    pub id: u64,
+    /// Operation followed by its arguments, each argument may hold arbitrary bytes
+    pub args: Vec<Vec<u8>>,
+    /// Session the request belongs to, a retry of the request carries the same one
+    pub session: Option<Session>,
+}
+
+/// Identifies a request across retries. `client` is chosen at random by the client for its
+/// lifetime and `seq` is unique among its requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Session {
+    pub client: u64,
+    pub seq: u64,
+}
+
+/// Reply sent back on the connection the request arrived on
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientResponse {
+    pub id: u64,
+    pub response: Response,
+}
+
+/// Key returned by a scan, with the log index of the entry that last wrote it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub version: u64,
+}
+
+/// Write or delete of a key as recorded in the log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Change {
+    /// Log index of the entry
+    pub index: u64,
+    /// Value written, `None` for a delete
+    pub value: Option<Vec<u8>>,
+    /// Client that issued the request
+    pub client: String,
+    /// When the request was proposed, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// Change delivered to a watch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WatchEvent {
+    pub key: Vec<u8>,
+    pub change: Change,
+}
+
+/// Reply sent from a server node to the cli client
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// Value of a read key as of log index `index`, `None` if the key does not exist. `version` is
+    /// the index of the entry that last wrote or deleted it, 0 if it was never written.
+    Value { value: Option<Vec<u8>>, version: u64, index: u64 },
+    /// Entries of a range or prefix scan in key order as of log index `index`, `next` is the token
+    /// for the following page
+    Scan { entries: Vec<Entry>, next: Option<Vec<u8>>, index: u64 },
+    /// Changes to a key, newest first. `truncated` is set if older changes of the key have been
+    /// discarded, only the latest 100 changes of a key are kept.
+    History { key: Vec<u8>, changes: Vec<Change>, truncated: bool },
+    /// Change to a watched key, a watch gets one reply per change
+    Watch(WatchEvent),
+    /// Outcome of a compare-and-swap, with the value the key held when it was evaluated
+    Cas { key: Vec<u8>, success: bool, current: Option<Vec<u8>> },
+    /// A write or delete has been decided
+    Committed,
+    /// Outcome of a transaction, `failed` describes the condition that aborted it
+    Transaction { committed: bool, failed: Option<String> },
+    /// The request could not be proposed
+    Error(String),
+    /// The request was not decided in time, it may or may not still take effect
+    Timeout,
+    /// The request has to be sent to the leader instead
+    Redirect { leader: u64 },
+    /// Current leader as seen by the node, `None` if no leader is known
+    Leader(Option<u64>),
+    /// Whether the node is ready, the index its store is at and the leader it knows of
+    Status { status: Status, applied: u64, leader: Option<u64> },
+}
+
+/// Status of a node, a restarted node is recovering until it has rejoined the cluster
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    Recovering,
+    Ready,
+}
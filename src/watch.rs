@@ -0,0 +1,119 @@
+use log::debug;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, Mutex};
+
+use crate::store::KVStore;
+use crate::util::WatchEvent;
+
+/// Number of decided changes buffered for watchers that are slow to receive them
+const WATCH_BUFFER: usize = 1024;
+
+/// Keys a watch delivers changes for
+#[derive(Clone, Debug)]
+pub enum Watched {
+    Key(Vec<u8>),
+    Prefix(Vec<u8>),
+}
+
+impl Watched {
+    pub fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            Watched::Key(watched) => key == watched.as_slice(),
+            Watched::Prefix(prefix) => key.starts_with(prefix),
+        }
+    }
+}
+
+/// What the Omni-paxos task publishes to the watches
+#[derive(Clone, Debug)]
+enum Published {
+    Change(WatchEvent),
+    /// The store was restored from a snapshot at this index, skipping the changes before it
+    Restored(u64),
+}
+
+/// Fans the changes applied by the Omni-paxos task out to every watch
+pub struct Watchers {
+    events: broadcast::Sender<Published>,
+}
+
+impl Watchers {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(WATCH_BUFFER);
+        Watchers { events }
+    }
+
+    /// Deliver changes taken from the store. Called with the store locked, so a watch started
+    /// meanwhile gets every change either replayed or delivered, never both.
+    pub fn publish(&self, changes: Vec<WatchEvent>) {
+        for change in changes {
+            // fails only if nobody is watching
+            let _ = self.events.send(Published::Change(change));
+        }
+    }
+
+    /// End every watch after the store was restored from a snapshot at log index `index`, e.g. when
+    /// a lagging node catches up. The changes before it are not delivered, so the watches are told
+    /// to read the current values and resume after it.
+    pub fn restored(&self, index: u64) {
+        let _ = self.events.send(Published::Restored(index));
+    }
+
+    /// Start a watch. Changes decided from log index `from` on are replayed from the store before
+    /// new ones are delivered, without `from` only new changes are. The watch ends with an error
+    /// if it falls too far behind, naming the index to resume from (changes at that index may be
+    /// delivered twice), if the store is restored from a snapshot, or once the receiver is dropped.
+    pub async fn watch(
+        &self,
+        kv_store: &Mutex<KVStore>,
+        watched: Watched,
+        from: Option<u64>,
+    ) -> Result<mpsc::Receiver<Result<WatchEvent, String>>, String> {
+        let (replay, mut live, mut resume) = {
+            let store = kv_store.lock().await;
+            let replay = match from {
+                Some(from) => store.changes_since(&watched, from)?,
+                None => vec![],
+            };
+            (replay, self.events.subscribe(), from.unwrap_or(store.applied() + 1))
+        };
+
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        tokio::spawn(async move {
+            for event in replay {
+                resume = event.change.index;
+                if sender.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                match live.recv().await {
+                    Ok(Published::Change(event)) if watched.matches(&event.key) => {
+                        resume = event.change.index;
+                        if sender.send(Ok(event)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Published::Change(..)) => {}
+                    Ok(Published::Restored(index)) => {
+                        debug!("Watch on {:?} ended by a restore at index {}", watched, index);
+                        let e = format!(
+                            "store restored from a snapshot at index {}, changes before it were skipped: read the current values and resume from index {}",
+                            index,
+                            index + 1
+                        );
+                        let _ = sender.send(Err(e)).await;
+                        return;
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("Watch on {:?} missed {} changes", watched, skipped);
+                        let _ = sender.send(Err(format!("watch fell behind, resume from index {}", resume))).await;
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}